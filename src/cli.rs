@@ -20,4 +20,6 @@ pub struct Cli {
     pub mqtt_topic_subscribe: String,
     #[clap(long = "mqtt_topic_publish", default_value = "from_dehneEVSE")]
     pub mqtt_topic_publish: String,
+    #[clap(long = "mqtt_topic_status", default_value = "dehneevse_mqtt_bridge/status")]
+    pub mqtt_topic_status: String,
 }
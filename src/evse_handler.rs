@@ -1,149 +1,1049 @@
+use crate::limits::EvseLimits;
 use crate::protocol::{
-    evse_to_mqtt, mqtt_to_evse, MqttMessage, MqttMessageHandshake, MqttMessageType,
+    evse_to_mqtt, ErrorCode, EvseCodec, EvseFrame, MqttMessage, MqttMessageConnection,
+    MqttMessageError, MqttMessageHandshake, MqttMessageMeasurements, MqttMessagePower,
+    MqttMessageRejection, MqttMessageType, MqttMessageVehicleState, PilotVoltage,
 };
-use crate::utils::bytes_to_hex;
-use byteorder::{BigEndian, ByteOrder};
+use crate::registry::{CommandOrigin, ConnectionRegistry, DuplicatePolicy};
+use crate::schedule::Schedule;
+use crate::utils::{bytes_to_hex, evse_setting};
+use bytes::BytesMut;
+use chrono::Local;
 use config::Config;
+use futures::StreamExt;
 use log::{error, info};
+use std::collections::VecDeque;
 use std::error;
 use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, watch};
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_util::codec::{Encoder, FramedRead};
 
 type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
 
+/// A request written to the EVSE, waiting for its response.
+struct PendingResponse {
+    request_type: MqttMessageType,
+    response_type: MqttMessageType,
+    request_id: Option<String>,
+    /// Requested by the bridge itself, pongs answering these are not forwarded to MQTT
+    internal: bool,
+}
+
+/// Encoded requests waiting to be written to the EVSE, oldest first. Frames are only removed
+/// once written completely, so the EVSE receives them in order even if the socket is slow.
+struct CommandQueue {
+    encoder: EvseCodec,
+    frames: VecDeque<(BytesMut, Option<PendingResponse>)>,
+    depth: usize,
+}
+
+impl CommandQueue {
+    fn new(encoder: EvseCodec, depth: usize) -> CommandQueue {
+        CommandQueue {
+            encoder,
+            frames: VecDeque::new(),
+            depth,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Queues a request from MQTT unless `depth` requests are waiting already.
+    fn push(&mut self, msg: MqttMessage) -> Result<bool> {
+        if self.frames.len() >= self.depth {
+            return Ok(false);
+        }
+        self.enqueue(msg, false)?;
+        Ok(true)
+    }
+
+    /// Queues a request of the bridge itself regardless of `depth`.
+    fn push_always(&mut self, msg: MqttMessage) -> Result<()> {
+        self.enqueue(msg, true)
+    }
+
+    fn enqueue(&mut self, msg: MqttMessage, internal: bool) -> Result<()> {
+        let pending = msg
+            .message_type
+            .response_type()
+            .map(|response_type| PendingResponse {
+                request_type: msg.message_type.clone(),
+                response_type,
+                request_id: msg.request_id.clone(),
+                internal,
+            });
+        let mut frame = BytesMut::new();
+        self.encoder.encode(msg, &mut frame)?;
+        self.frames.push_back((frame, pending));
+        Ok(())
+    }
+
+    /// Removes the first frame once it has been written completely, returning the response
+    /// to wait for.
+    fn remove_written(&mut self) -> Option<PendingResponse> {
+        match self.frames.front() {
+            Some((frame, _)) if frame.is_empty() => self.frames.pop_front().unwrap().1,
+            _ => None,
+        }
+    }
+}
+
 pub async fn handle_evse(
-    mut mqtt_evse_rx: broadcast::Receiver<MqttMessage>,
+    registry: ConnectionRegistry,
     evse_mqtt_tx: broadcast::Sender<MqttMessage>,
     mut socket: TcpStream,
     peer_addr: SocketAddr,
     mut shutdown_rx: broadcast::Receiver<bool>,
+    mut broker_connected_rx: watch::Receiver<bool>,
     settings: Config,
 ) -> Result<()> {
     info!("EVSE: Accepted new EVSE connection from {}", peer_addr);
 
-    let (mut tcp_rx, mut tcp_tx) = socket.split();
+    let (tcp_rx, mut tcp_tx) = socket.split();
+    let max_frame_size = settings.get_int("evse.max_frame_size").unwrap_or(1024) as usize;
+    let mut frames = FramedRead::new(tcp_rx, EvseCodec::new(max_frame_size));
 
     // client starts by sending welcome message:
-    let mut buf: [u8; 16] = [0; 16];
-    tcp_rx.read_exact(&mut buf).await.map_err(|err| {
-        Error::new(
-            ErrorKind::InvalidData,
-            format!("Error reading the serial ID: {}", err),
-        )
-    })?;
-    let client_serial = bytes_to_hex(&buf);
+    let (serial, firmware_version) = match frames.next().await {
+        Some(Ok(EvseFrame::Handshake {
+            serial,
+            firmware_version,
+        })) => (serial, firmware_version),
+        Some(Ok(frame)) => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Expected the handshake, received {:?}", frame),
+            )
+            .into())
+        }
+        Some(Err(err)) => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Error reading the handshake: {}", err),
+            )
+            .into())
+        }
+        None => {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed before the handshake",
+            )
+            .into())
+        }
+    };
+    let client_serial = bytes_to_hex(&serial);
     let client_id = settings
         .get_string(&format!("evse_name.id_{}", client_serial))
-        .unwrap_or(client_serial);
-
-    let mut buf: [u8; 1] = [0];
-    tcp_rx.read_exact(&mut buf).await.map_err(|err| {
-        Error::new(
-            ErrorKind::InvalidData,
-            format!("Error reading the firmware version: {}", err),
-        )
-    })?;
-    let firmware_version = buf[0];
+        .unwrap_or_else(|_| client_serial.clone());
+    let limits = EvseLimits::from_settings(&settings, &client_serial);
+    // both are validated on startup
+    let schedule = Schedule::from_settings(&settings, &client_serial)?;
+    let duplicate_policy = DuplicatePolicy::from_settings(&settings, &client_serial)?;
+    let handshake = MqttMessageHandshake {
+        serial: client_serial.clone(),
+        firmware_version,
+        limits: limits.clone(),
+    };
+    let (mut registration, previous_peer_addr) =
+        match registry.register(&client_id, peer_addr, handshake.clone(), duplicate_policy) {
+            Ok(registered) => registered,
+            Err(registered) => {
+                evse_mqtt_tx.send(MqttMessage {
+                    connection: Some(MqttMessageConnection {
+                        peer_addr: peer_addr.to_string(),
+                        previous_peer_addr: registered.to_string(),
+                    }),
+                    ..MqttMessage::new(MqttMessageType::connection_rejected, client_id.clone())
+                })?;
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!(
+                        "{} is connected from {} already, refusing the connection from {}",
+                        client_id, registered, peer_addr
+                    ),
+                )
+                .into());
+            }
+        };
 
     let payload = MqttMessage {
-        message_type: MqttMessageType::new_connection,
-        client_id: client_id.clone(),
-        handshake: Some(MqttMessageHandshake { firmware_version }),
-        firmware: None,
-        pwm_percent: None,
-        contactor_state: None,
-        measurements: None,
+        handshake: Some(handshake),
+        ..MqttMessage::new(MqttMessageType::new_connection, client_id.clone())
     };
 
     evse_mqtt_tx.send(payload)?;
 
-    let mut message_header_buf = [0u8; 5];
-    let mut bytes_to_write: Vec<u8> = vec![];
+    if let Some(previous_peer_addr) = previous_peer_addr {
+        info!(
+            "EVSE: {} connected again from {}, closing the connection from {}",
+            client_id, peer_addr, previous_peer_addr
+        );
+        evse_mqtt_tx.send(MqttMessage {
+            connection: Some(MqttMessageConnection {
+                peer_addr: peer_addr.to_string(),
+                previous_peer_addr: previous_peer_addr.to_string(),
+            }),
+            ..MqttMessage::new(MqttMessageType::connection_replaced, client_id.clone())
+        })?;
+    }
 
-    loop {
-        tokio::select! {
-            Ok(_) = shutdown_rx.recv() => {
-                info!("EVSE: Closing connection to {} due to shutdown", client_id);
-                break;
-            }
-            sendt = tcp_tx.write_all(&bytes_to_write[..]), if bytes_to_write.len() > 0 => {
-                match sendt {
-                    Err(err) => {
-                        error!("EVSE: Error writing to EVSE {}: {}", client_id, err);
-                        break;
+    // The EVSE drops the connection if it does not receive a request at least every 10 seconds,
+    // so the bridge polls it by itself and sends a ping on every keepalive tick where no poll is due.
+    let poll_enabled: bool = evse_setting(&settings, &client_serial, "poll_enabled").unwrap_or(true);
+    let poll_interval = Duration::from_secs(
+        evse_setting(&settings, &client_serial, "poll_interval_seconds").unwrap_or(5),
+    );
+    let mut keepalive = tokio::time::interval(Duration::from_secs(
+        evse_setting(&settings, &client_serial, "keepalive_interval_seconds").unwrap_or(5),
+    ));
+    keepalive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_poll: Option<Instant> = None;
+    // the cable rating of the last data collection limits the charge current
+    let mut last_measurements: Option<MqttMessageMeasurements> = None;
+    // energy counter of this connection, starting from 0 on every reconnect
+    let mut energy_kwh = 0.0f64;
+    let mut last_power: Option<(f32, Instant)> = None;
+
+    // Fail the charging station to a safe state (no charging, contactor open) if its controller
+    // stops sending commands or the MQTT broker is lost. Only commands from MQTT count as a sign
+    // of life, the failsafe stays engaged until the next one arrives. Commands of the bridge
+    // itself (load balancing, planner) are not sent while it is engaged.
+    let watchdog_timeout =
+        match evse_setting(&settings, &client_serial, "watchdog_timeout_seconds").unwrap_or(0) {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        };
+    let failsafe_on_broker_loss: bool =
+        evse_setting(&settings, &client_serial, "failsafe_on_broker_loss").unwrap_or(false);
+    let mut last_command = Instant::now();
+    let mut failsafe_engaged = false;
+    let mut failsafe_reason: Option<String> = None;
+    // published once the broker is reachable again
+    let mut failsafe_event: Option<MqttMessage> = None;
+    // the last charge current of the bridge dropped while engaged, restored once the failsafe
+    // clears as load balancing and the planner consider it sent already
+    let mut dropped_current: Option<MqttMessage> = None;
+    if failsafe_on_broker_loss && !*broker_connected_rx.borrow() {
+        failsafe_reason = Some("MQTT broker is not connected".to_string());
+    }
+
+    // Outside the charging windows of the schedule the charging station is held at 100% PWM and
+    // commands which would start charging are deferred until the next window opens, unless
+    // charge_now has been requested for the vehicle currently plugged in.
+    let mut charging_allowed: Option<bool> = None;
+    let mut charge_now = false;
+    // the last charge current requested, sent again when a window opens
+    let mut requested_current: Option<MqttMessage> = None;
+    let mut deferred_contactor: Option<MqttMessage> = None;
+
+    // Requests from MQTT are dropped while the queue is full, requests of the bridge itself
+    // (polling, failsafe, interlock, schedule) are always queued.
+    let mut queue = CommandQueue::new(
+        EvseCodec::new(max_frame_size),
+        evse_setting(&settings, &client_serial, "command_queue_depth").unwrap_or(16),
+    );
+    // requests written to the EVSE and when their response is due at the latest
+    let mut awaiting: VecDeque<(Instant, PendingResponse)> = VecDeque::new();
+    // requests which timed out, kept for another timeout period to recognize late responses
+    let mut timed_out: VecDeque<(Instant, PendingResponse)> = VecDeque::new();
+    let response_timeout = Duration::from_secs(
+        evse_setting(&settings, &client_serial, "response_timeout_seconds").unwrap_or(10),
+    );
+
+    let result: Result<()> = async {
+        loop {
+            let queue_empty = queue.is_empty();
+            let next_deadline = awaiting.front().map(|(deadline, _)| *deadline);
+            let write_to_evse = async {
+                match queue.frames.front_mut() {
+                    Some((frame, _)) => tcp_tx.write_buf(frame).await,
+                    None => Ok(0),
+                }
+            };
+
+            tokio::select! {
+                Ok(_) = shutdown_rx.recv() => {
+                    info!("EVSE: Closing connection to {} due to shutdown", client_id);
+                    break;
+                }
+                written = write_to_evse, if !queue_empty => {
+                    match written {
+                        Err(err) => {
+                            error!("EVSE: Error writing to EVSE {}: {}", client_id, err);
+                            break;
+                        }
+                        Ok(0) => {
+                            error!("EVSE: Connection to {} closed while writing", client_id);
+                            break;
+                        }
+                        Ok(_) => {
+                            if let Some(pending) = queue.remove_written() {
+                                awaiting.push_back((Instant::now() + response_timeout, pending));
+                            }
+                        }
                     }
-                    Ok(_) => bytes_to_write = vec![]
                 }
-            }
-            receive = mqtt_evse_rx.recv() => {
-                match receive {
-                    Err(err) => error!("EVSE: Error reading from MQTT {}: {}", client_id, err),
-                    Ok(mqtt_message) => {
-                        if mqtt_message.client_id == client_id {
-                            info!("EVSE: Sending msg to EVSE {:?}", mqtt_message);
-                            match mqtt_to_evse(mqtt_message) {
-                                Ok(v) => bytes_to_write = v,
-                                Err(err) => {
-                                    error!("EVSE: Error translating MQTT-message to EVSE-message {}: {}", client_id, err);
+                _ = tokio::time::sleep_until(next_deadline.unwrap_or_else(Instant::now)), if next_deadline.is_some() => {
+                    let now = Instant::now();
+                    while matches!(awaiting.front(), Some((deadline, _)) if *deadline <= now) {
+                        let (_, pending) = awaiting.pop_front().unwrap();
+                        if pending.internal {
+                            info!("EVSE: {} did not answer {:?} within {:?}", client_id, pending.request_type, response_timeout);
+                        } else {
+                            error!("EVSE: {} did not answer {:?} within {:?}", client_id, pending.request_type, response_timeout);
+                            evse_mqtt_tx.send(MqttMessage {
+                                request_id: pending.request_id.clone(),
+                                rejection: Some(MqttMessageRejection {
+                                    request_type: pending.request_type.clone(),
+                                    reason: format!("no response within {:?}", response_timeout),
+                                }),
+                                ..MqttMessage::new(MqttMessageType::response_timeout, client_id.clone())
+                            })?;
+                        }
+                        timed_out.push_back((now + response_timeout, pending));
+                    }
+                }
+                now = keepalive.tick(), if queue_empty => {
+                    let poll_due = poll_enabled
+                        && !matches!(last_poll, Some(last) if now.duration_since(last) < poll_interval);
+                    let message_type = if poll_due {
+                        last_poll = Some(now);
+                        MqttMessageType::request_data_collection
+                    } else {
+                        MqttMessageType::request_ping
+                    };
+                    queue.push_always(MqttMessage::new(message_type, client_id.clone()))?;
+                }
+                _ = tokio::time::sleep_until(last_command + watchdog_timeout.unwrap_or_default()), if watchdog_timeout.is_some() && !failsafe_engaged => {
+                    failsafe_reason = Some(format!("no command received within {:?}", watchdog_timeout.unwrap()));
+                }
+                Ok(_) = broker_connected_rx.changed() => {
+                    let broker_connected = *broker_connected_rx.borrow();
+                    if broker_connected {
+                        if let Some(event) = failsafe_event.take() {
+                            evse_mqtt_tx.send(event)?;
+                        }
+                    } else if failsafe_on_broker_loss && !failsafe_engaged {
+                        failsafe_reason = Some("MQTT broker connection lost".to_string());
+                    }
+                }
+                receive = registration.commands.recv() => {
+                    match receive {
+                        None => {
+                            info!("EVSE: Closing connection to {} from {}, replaced by a newer one", client_id, peer_addr);
+                            break;
+                        }
+                        Some((mqtt_message, origin)) => {
+                            if matches!(mqtt_message.message_type, MqttMessageType::request_charge_now) {
+                                charge_now = mqtt_message.charge_now.unwrap_or(true);
+                                info!("EVSE: Setting charge_now={} for {}", charge_now, client_id);
+                                evse_mqtt_tx.send(MqttMessage {
+                                    request_id: mqtt_message.request_id.clone(),
+                                    charge_now: Some(charge_now),
+                                    ..MqttMessage::new(MqttMessageType::response_charge_now, client_id.clone())
+                                })?;
+                            }
+                            if origin == CommandOrigin::Mqtt {
+                                last_command = Instant::now();
+                                if failsafe_engaged {
+                                    info!("EVSE: Clearing failsafe for {}, {:?} received", client_id, mqtt_message.message_type);
+                                    if let Some(event) = failsafe_event.take() {
+                                        evse_mqtt_tx.send(event)?;
+                                    }
+                                    evse_mqtt_tx.send(MqttMessage {
+                                        reason: Some(format!("{:?} received", mqtt_message.message_type)),
+                                        ..MqttMessage::new(MqttMessageType::failsafe_cleared, client_id.clone())
+                                    })?;
+                                    failsafe_engaged = false;
+                                    // a charge current sent along with the clearing command takes precedence
+                                    if let Some(command) = dropped_current.take().filter(|_| !mqtt_message.message_type.sets_current()) {
+                                        match limits.check(&command, last_measurements.as_ref()) {
+                                            Ok(command) => {
+                                                if charging_allowed.is_some() {
+                                                    requested_current = Some(command.clone());
+                                                }
+                                                if charging_allowed == Some(false) {
+                                                    info!("EVSE: Deferring msg to EVSE {:?} until the next charging window", command);
+                                                } else {
+                                                    info!("EVSE: Restoring msg to EVSE {:?} after the failsafe", command);
+                                                    queue.push_always(command)?;
+                                                }
+                                            }
+                                            Err(reason) => error!("EVSE: Not restoring {:?} for {}: {}", command.message_type, client_id, reason),
+                                        }
+                                    }
+                                }
+                            } else if failsafe_engaged {
+                                info!("EVSE: Not sending {:?} to {} while the failsafe is engaged", mqtt_message.message_type, client_id);
+                                if mqtt_message.message_type.sets_current() {
+                                    dropped_current = Some(mqtt_message);
+                                }
+                                continue;
+                            }
+                            if !mqtt_message.message_type.is_bridge_request() {
+                                let mqtt_message = match limits.check(&mqtt_message, last_measurements.as_ref()) {
+                                    Ok(mqtt_message) => mqtt_message,
+                                    Err(reason) => {
+                                        info!("EVSE: Rejecting msg to EVSE {:?}: {}", mqtt_message, reason);
+                                        evse_mqtt_tx.send(MqttMessage {
+                                            request_id: mqtt_message.request_id,
+                                            rejection: Some(MqttMessageRejection {
+                                                request_type: mqtt_message.message_type,
+                                                reason,
+                                            }),
+                                            ..MqttMessage::new(MqttMessageType::command_rejected, client_id.clone())
+                                        })?;
+                                        continue;
+                                    }
+                                };
+                                if charging_allowed.is_some() {
+                                    let starts_charging = match mqtt_message.message_type {
+                                        MqttMessageType::request_set_pwm_percent => {
+                                            requested_current = Some(mqtt_message.clone());
+                                            mqtt_message.pwm_percent != Some(100)
+                                        }
+                                        MqttMessageType::request_set_charge_current => {
+                                            requested_current = Some(mqtt_message.clone());
+                                            mqtt_message.charge_current != Some(0.0)
+                                        }
+                                        MqttMessageType::request_set_contactor_state => {
+                                            deferred_contactor = None;
+                                            mqtt_message.contactor_state == Some(true)
+                                        }
+                                        _ => false,
+                                    };
+                                    if starts_charging && charging_allowed == Some(false) {
+                                        info!("EVSE: Deferring msg to EVSE {:?} until the next charging window", mqtt_message);
+                                        if matches!(mqtt_message.message_type, MqttMessageType::request_set_contactor_state) {
+                                            deferred_contactor = Some(mqtt_message.clone());
+                                        }
+                                        // load balancing and the planner repeat their commands on their own
+                                        if origin == CommandOrigin::Mqtt {
+                                            evse_mqtt_tx.send(MqttMessage {
+                                                request_id: mqtt_message.request_id.clone(),
+                                                reason: Some(format!(
+                                                    "{:?} deferred until the next charging window",
+                                                    mqtt_message.message_type
+                                                )),
+                                                ..MqttMessage::new(MqttMessageType::command_deferred, client_id.clone())
+                                            })?;
+                                        }
+                                        continue;
+                                    }
+                                }
+                                info!("EVSE: Sending msg to EVSE {:?}", mqtt_message);
+                                let request_type = mqtt_message.message_type.clone();
+                                let request_id = mqtt_message.request_id.clone();
+                                match queue.push(mqtt_message) {
+                                    Ok(true) => {}
+                                    Ok(false) => {
+                                        error!("EVSE: Command queue of {} is full, dropping {:?}", client_id, request_type);
+                                        evse_mqtt_tx.send(MqttMessage {
+                                            request_id,
+                                            rejection: Some(MqttMessageRejection {
+                                                request_type,
+                                                reason: format!("command queue is full ({} requests waiting)", queue.depth),
+                                            }),
+                                            ..MqttMessage::new(MqttMessageType::command_dropped, client_id.clone())
+                                        })?;
+                                    }
+                                    Err(err) => {
+                                        error!("EVSE: Error translating MQTT-message to EVSE-message {}: {}", client_id, err);
+                                        evse_mqtt_tx.send(MqttMessage {
+                                            request_id,
+                                            error: Some(MqttMessageError {
+                                                code: ErrorCode::invalid_request,
+                                                message_type: Some(format!("{:?}", request_type)),
+                                                reason: err.to_string(),
+                                            }),
+                                            ..MqttMessage::new(MqttMessageType::error, client_id.clone())
+                                        })?;
+                                    }
                                 }
                             }
                         }
                     }
                 }
-            }
-            reading_result = tcp_rx.read_exact(&mut message_header_buf) => {
-                match reading_result {
-                    Ok(_size) => {
-                        let msg_type = message_header_buf[0];
-                        let msg_length = BigEndian::read_u32(&message_header_buf[1..5]);
-
-                        if msg_length > 1024 {
-                            error!("EVSE: received msg_length={} is too large, disconnecting", msg_length);
+                frame = frames.next() => {
+                    let (msg_type, payload) = match frame {
+                        Some(Ok(EvseFrame::Message { message_type, payload })) => (message_type, payload),
+                        Some(Ok(frame)) => {
+                            error!("EVSE: unexpected frame from {}: {:?}", client_id, frame);
                             break;
                         }
+                        Some(Err(e)) => {
+                            error!("EVSE: error while reading from {}: {}", client_id, e);
+                            break;
+                        }
+                        None => {
+                            info!("EVSE: {} disconnected", client_id);
+                            break;
+                        }
+                    };
 
-                        let mut payload_buf: Vec<u8> = Vec::with_capacity(msg_length as usize);
-                        match tcp_rx.read_exact(&mut payload_buf).await {
-                            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
-                                info!("EVSE: {} disconnected", client_id);
-                                break;
-                            }
-                            Err(e) => {
-                                error!("EVSE: error while reading from {}: {}", client_id, e);
-                                break;
-                            },
-                            _ => {}
-                        };
-
-                        let msg = match evse_to_mqtt(
-                            client_id.clone(),
-                            msg_type,
-                            msg_length,
-                            &payload_buf[..]
-                        ) {
-                            Ok(msg) => msg,
-                            Err(e) => {
-                                error!("EVSE: error while parsing data from {}: {}", client_id, e);
-                                break;
+                    let mut msg = match evse_to_mqtt(client_id.clone(), msg_type, &payload[..]) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            // the framing is intact, so only this message is lost
+                            error!("EVSE: error while parsing data from {}: {}", client_id, e);
+                            evse_mqtt_tx.send(MqttMessage {
+                                protocol_error: Some(e),
+                                ..MqttMessage::new(MqttMessageType::protocol_error, client_id.clone())
+                            })?;
+                            continue;
+                        }
+                    };
+
+                    // the EVSE answers in order, but a lost response must not shift all later ones
+                    let now = Instant::now();
+                    while matches!(timed_out.front(), Some((expiry, _)) if *expiry <= now) {
+                        timed_out.pop_front();
+                    }
+                    let pending = match awaiting.iter().position(|(_, pending)| pending.response_type == msg.message_type) {
+                        Some(i) => awaiting.remove(i),
+                        None => match timed_out.iter().position(|(_, pending)| pending.response_type == msg.message_type) {
+                            Some(i) => {
+                                info!("EVSE: Late {:?} from {}", msg.message_type, client_id);
+                                timed_out.remove(i)
                             }
-                        };
+                            None => None,
+                        },
+                    };
+                    if let Some((_, pending)) = pending {
+                        if pending.internal && msg.message_type == MqttMessageType::response_ping {
+                            continue;
+                        }
+                        msg.request_id = pending.request_id;
+                    }
 
-                        evse_mqtt_tx.send(msg)?;
+                    if matches!(msg.message_type, MqttMessageType::response_collect_data) {
+                        if limits.must_open_contactor(&msg) {
+                            info!("EVSE: Vehicle at {} is gone or faulted, opening the contactor", client_id);
+                            queue.push_always(MqttMessage {
+                                contactor_state: Some(false),
+                                ..MqttMessage::new(MqttMessageType::request_set_contactor_state, client_id.clone())
+                            })?;
+                        }
+                        if charge_now
+                            && matches!(msg.measurements.as_ref().map(|m| &m.pilot_voltage), Some(PilotVoltage::volt_12))
+                        {
+                            info!("EVSE: Vehicle at {} unplugged, returning to the schedule", client_id);
+                            charge_now = false;
+                        }
+                        if let Some(measurements) = &msg.measurements {
+                            let now = Instant::now();
+                            let power = measurements.power();
+                            if let Some((last_power, measured)) = last_power {
+                                energy_kwh += crate::utils::energy_kwh(last_power, power, now.duration_since(measured));
+                            }
+                            let [phase1_watts, phase2_watts, phase3_watts] = measurements.phase_powers();
+                            msg.power = Some(MqttMessagePower {
+                                phase1_watts,
+                                phase2_watts,
+                                phase3_watts,
+                                total_watts: power,
+                                active_phases: measurements.active_phases(),
+                                energy_kwh,
+                                energy_reset: last_power.is_none(),
+                            });
+                            last_power = Some((power, now));
+                        }
+                        if let (Some(previous), Some(current)) = (&last_measurements, &msg.measurements) {
+                            if previous.vehicle_state != current.vehicle_state {
+                                info!("EVSE: Vehicle state of {} changed from {:?} to {:?}", client_id, previous.vehicle_state, current.vehicle_state);
+                                evse_mqtt_tx.send(MqttMessage {
+                                    vehicle_state: Some(MqttMessageVehicleState {
+                                        previous: previous.vehicle_state,
+                                        current: current.vehicle_state,
+                                        meaning: current.vehicle_state.meaning().to_string(),
+                                    }),
+                                    ..MqttMessage::new(MqttMessageType::vehicle_state_changed, client_id.clone())
+                                })?;
+                            }
+                        }
+                        last_measurements = msg.measurements.clone();
                     }
-                    Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
-                        info!("EVSE: {} disconnected", client_id);
-                        break;
+
+                    evse_mqtt_tx.send(msg)?;
+                }
+            }
+
+            if let Some(reason) = failsafe_reason.take() {
+                info!("EVSE: Engaging failsafe for {}: {}", client_id, reason);
+                failsafe_engaged = true;
+                queue.push_always(MqttMessage {
+                    pwm_percent: Some(100),
+                    ..MqttMessage::new(MqttMessageType::request_set_pwm_percent, client_id.clone())
+                })?;
+                queue.push_always(MqttMessage {
+                    contactor_state: Some(false),
+                    ..MqttMessage::new(MqttMessageType::request_set_contactor_state, client_id.clone())
+                })?;
+
+                let event = MqttMessage {
+                    reason: Some(reason),
+                    ..MqttMessage::new(MqttMessageType::failsafe_engaged, client_id.clone())
+                };
+                if *broker_connected_rx.borrow() {
+                    evse_mqtt_tx.send(event)?;
+                } else {
+                    failsafe_event = Some(event);
+                }
+            }
+
+            if let Some(schedule) = &schedule {
+                let allowed = charge_now || schedule.is_open(Local::now().naive_local());
+                if charging_allowed != Some(allowed) {
+                    charging_allowed = Some(allowed);
+                    let mut commands = vec![];
+                    if allowed && failsafe_engaged {
+                        info!("EVSE: Charging window opened for {}, but the failsafe is engaged", client_id);
+                    } else if allowed {
+                        info!("EVSE: Charging window opened for {}", client_id);
+                        commands.push(requested_current.clone().unwrap_or(MqttMessage {
+                            charge_current: Some(schedule.current),
+                            ..MqttMessage::new(MqttMessageType::request_set_charge_current, client_id.clone())
+                        }));
+                        commands.extend(deferred_contactor.take());
+                    } else {
+                        info!("EVSE: Charging window closed for {}", client_id);
+                        commands.push(MqttMessage {
+                            pwm_percent: Some(100),
+                            ..MqttMessage::new(MqttMessageType::request_set_pwm_percent, client_id.clone())
+                        });
                     }
-                    Err(e) => {
-                        error!("EVSE: error while reading from {}: {}", client_id, e);
-                        break;
+                    for command in commands {
+                        match limits.check(&command, last_measurements.as_ref()) {
+                            Ok(command) => queue.push_always(command)?,
+                            Err(reason) => error!("EVSE: Not sending {:?} to {}: {}", command.message_type, client_id, reason),
+                        }
                     }
                 }
             }
         }
+        Ok(())
+    }
+    .await;
+
+    // commands sent from now on are answered with not_connected. A connection replaced by a newer
+    // one does not publish connection_closed, even if it noticed its peer leaving first, as the
+    // charging station is still connected.
+    if registration.unregister() {
+        evse_mqtt_tx.send(MqttMessage::new(
+            MqttMessageType::connection_closed,
+            client_id,
+        ))?;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn ping(request_id: &str) -> MqttMessage {
+        MqttMessage {
+            request_id: Some(request_id.to_string()),
+            ..MqttMessage::new(MqttMessageType::request_ping, "evse".to_string())
+        }
+    }
+
+    /// Simulates the socket writing the first frame completely.
+    fn write_first(queue: &mut CommandQueue) -> Option<PendingResponse> {
+        queue.frames.front_mut().unwrap().0.clear();
+        queue.remove_written()
+    }
+
+    #[test]
+    fn drops_requests_from_mqtt_while_full() {
+        let mut queue = CommandQueue::new(EvseCodec::new(1024), 2);
+        assert!(queue.push(ping("1")).unwrap());
+        assert!(queue.push(ping("2")).unwrap());
+        assert!(!queue.push(ping("3")).unwrap());
+        assert_eq!(queue.frames.len(), 2);
+    }
+
+    #[test]
+    fn always_queues_requests_of_the_bridge() {
+        let mut queue = CommandQueue::new(EvseCodec::new(1024), 1);
+        assert!(queue.push(ping("1")).unwrap());
+        queue.push_always(ping("2")).unwrap();
+        assert_eq!(queue.frames.len(), 2);
+        assert!(!queue.push(ping("3")).unwrap());
+    }
+
+    #[test]
+    fn writes_requests_in_order() {
+        let mut queue = CommandQueue::new(EvseCodec::new(1024), 16);
+        queue.push(ping("1")).unwrap();
+        queue
+            .push(MqttMessage {
+                pwm_percent: Some(16),
+                ..MqttMessage::new(MqttMessageType::request_set_pwm_percent, "evse".to_string())
+            })
+            .unwrap();
+        queue.push_always(ping("3")).unwrap();
+
+        let pending = write_first(&mut queue).unwrap();
+        assert_eq!(pending.response_type, MqttMessageType::response_ping);
+        assert_eq!(pending.request_id.as_deref(), Some("1"));
+        assert!(!pending.internal);
+        let pending = write_first(&mut queue).unwrap();
+        assert_eq!(
+            pending.request_type,
+            MqttMessageType::request_set_pwm_percent
+        );
+        assert_eq!(
+            pending.response_type,
+            MqttMessageType::response_set_pwm_percent
+        );
+        assert_eq!(pending.request_id, None);
+        let pending = write_first(&mut queue).unwrap();
+        assert_eq!(pending.request_id.as_deref(), Some("3"));
+        assert!(pending.internal);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn keeps_partially_written_frames() {
+        let mut queue = CommandQueue::new(EvseCodec::new(1024), 16);
+        queue.push(ping("1")).unwrap();
+        bytes::Buf::advance(&mut queue.frames[0].0, 2);
+        assert!(queue.remove_written().is_none());
+        assert_eq!(&queue.frames[0].0[..], &[0, 0, 0]);
+        assert!(write_first(&mut queue).is_some());
+    }
+
+    #[test]
+    fn does_not_queue_requests_which_cannot_be_encoded() {
+        let mut queue = CommandQueue::new(EvseCodec::new(1024), 16);
+        let msg = MqttMessage::new(MqttMessageType::request_set_pwm_percent, "evse".to_string());
+        assert!(queue.push(msg).is_err());
+        assert!(queue.is_empty());
+    }
+
+    /// A charging station connected to `handle_evse` through a local socket.
+    struct FakeEvse {
+        socket: TcpStream,
+        client_id: String,
+        _shutdown_tx: broadcast::Sender<bool>,
+        _broker_connected_tx: watch::Sender<bool>,
+        events: broadcast::Receiver<MqttMessage>,
+    }
+
+    impl FakeEvse {
+        async fn connect(
+            registry: &ConnectionRegistry,
+            settings: Config,
+            broker_connected: bool,
+        ) -> FakeEvse {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut socket = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (evse_socket, peer_addr) = listener.accept().await.unwrap();
+            let (evse_mqtt_tx, evse_mqtt_rx) = broadcast::channel(64);
+            let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+            let (broker_connected_tx, broker_connected_rx) = watch::channel(broker_connected);
+            let registry = registry.clone();
+            tokio::spawn(async move {
+                handle_evse(
+                    registry,
+                    evse_mqtt_tx,
+                    evse_socket,
+                    peer_addr,
+                    shutdown_rx,
+                    broker_connected_rx,
+                    settings,
+                )
+                .await
+                .unwrap_or_else(|err| error!("EVSE: connection failed: {}", err));
+            });
+
+            let serial = [0x10u8; 16];
+            socket.write_all(&serial).await.unwrap();
+            socket.write_all(&[1]).await.unwrap();
+            FakeEvse {
+                socket,
+                client_id: bytes_to_hex(&serial),
+                _shutdown_tx: shutdown_tx,
+                _broker_connected_tx: broker_connected_tx,
+                events: evse_mqtt_rx,
+            }
+        }
+
+        /// The next request written to the charging station as message type and payload.
+        async fn request(&mut self) -> (u8, Vec<u8>) {
+            let mut header = [0u8; 5];
+            self.socket.read_exact(&mut header).await.unwrap();
+            let mut payload =
+                vec![0u8; u32::from_be_bytes(header[1..].try_into().unwrap()) as usize];
+            self.socket.read_exact(&mut payload).await.unwrap();
+            (header[0], payload)
+        }
+
+        async fn respond(&mut self, message_type: u8, payload: &[u8]) {
+            self.socket.write_all(&[message_type]).await.unwrap();
+            self.socket
+                .write_all(&(payload.len() as u32).to_be_bytes())
+                .await
+                .unwrap();
+            self.socket.write_all(payload).await.unwrap();
+        }
+
+        /// The next message of the given type published to MQTT.
+        async fn event(&mut self, message_type: MqttMessageType) -> MqttMessage {
+            loop {
+                let msg = self.events.recv().await.unwrap();
+                if msg.message_type == message_type {
+                    return msg;
+                }
+            }
+        }
+    }
+
+    const PING: u8 = 1;
+    const SET_PWM_PERCENT: u8 = 4;
+    const SET_CONTACTOR_STATE: u8 = 5;
+    const RESPONSE_PONG: u8 = 1;
+    const RESPONSE_SET_PWM_PERCENT: u8 = 3;
+
+    /// Settings without polling, so only the first keepalive tick sends a ping.
+    fn settings(overrides: &[(&str, config::Value)]) -> Config {
+        let mut builder = Config::builder()
+            .set_override("evse.poll_enabled", false)
+            .unwrap()
+            .set_override("evse.keepalive_interval_seconds", 3600)
+            .unwrap();
+        for (key, value) in overrides {
+            builder = builder.set_override(*key, value.clone()).unwrap();
+        }
+        builder.build().unwrap()
+    }
+
+    fn charge_current(client_id: &str, charge_current: f32) -> MqttMessage {
+        MqttMessage {
+            charge_current: Some(charge_current),
+            ..MqttMessage::new(
+                MqttMessageType::request_set_charge_current,
+                client_id.to_string(),
+            )
+        }
+    }
+
+    /// Connects with the broker unreachable, so the failsafe engages right away.
+    async fn connect_with_failsafe(registry: &ConnectionRegistry) -> FakeEvse {
+        let mut evse = FakeEvse::connect(
+            registry,
+            settings(&[("evse.failsafe_on_broker_loss", true.into())]),
+            false,
+        )
+        .await;
+        assert_eq!(evse.request().await, (PING, vec![]));
+        assert_eq!(evse.request().await, (SET_PWM_PERCENT, vec![100]));
+        assert_eq!(evse.request().await, (SET_CONTACTOR_STATE, vec![0]));
+        evse
+    }
+
+    #[tokio::test]
+    async fn restores_bridge_current_after_failsafe() {
+        let registry = ConnectionRegistry::new(16);
+        let mut evse = connect_with_failsafe(&registry).await;
+
+        registry
+            .send(charge_current(&evse.client_id, 10.0), CommandOrigin::Bridge)
+            .unwrap();
+        registry
+            .send(
+                MqttMessage::new(MqttMessageType::request_ping, evse.client_id.clone()),
+                CommandOrigin::Mqtt,
+            )
+            .unwrap();
+
+        assert_eq!(evse.request().await, (SET_PWM_PERCENT, vec![16]));
+        assert_eq!(evse.request().await, (PING, vec![]));
+    }
+
+    #[tokio::test]
+    async fn clearing_charge_current_takes_precedence() {
+        let registry = ConnectionRegistry::new(16);
+        let mut evse = connect_with_failsafe(&registry).await;
+
+        registry
+            .send(charge_current(&evse.client_id, 10.0), CommandOrigin::Bridge)
+            .unwrap();
+        registry
+            .send(charge_current(&evse.client_id, 6.0), CommandOrigin::Mqtt)
+            .unwrap();
+        registry
+            .send(
+                MqttMessage::new(MqttMessageType::request_ping, evse.client_id.clone()),
+                CommandOrigin::Mqtt,
+            )
+            .unwrap();
+
+        assert_eq!(evse.request().await, (SET_PWM_PERCENT, vec![10]));
+        assert_eq!(evse.request().await, (PING, vec![]));
+    }
+
+    #[tokio::test]
+    async fn publishes_dropped_requests() {
+        let registry = ConnectionRegistry::new(16);
+        let mut evse = FakeEvse::connect(
+            &registry,
+            settings(&[("evse.command_queue_depth", 0.into())]),
+            true,
+        )
+        .await;
+        assert_eq!(evse.request().await, (PING, vec![]));
+
+        registry
+            .send(
+                MqttMessage {
+                    request_id: Some("1".to_string()),
+                    ..charge_current(&evse.client_id, 10.0)
+                },
+                CommandOrigin::Mqtt,
+            )
+            .unwrap();
+        let dropped = evse.event(MqttMessageType::command_dropped).await;
+        assert_eq!(dropped.request_id.as_deref(), Some("1"));
+        assert_eq!(
+            dropped.rejection.unwrap().request_type,
+            MqttMessageType::request_set_charge_current
+        );
+    }
+
+    fn ping_for(client_id: &str, request_id: &str) -> MqttMessage {
+        MqttMessage {
+            request_id: Some(request_id.to_string()),
+            ..MqttMessage::new(MqttMessageType::request_ping, client_id.to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn echoes_request_id_on_response() {
+        let registry = ConnectionRegistry::new(16);
+        let mut evse = FakeEvse::connect(&registry, settings(&[]), true).await;
+        // the pong answering the keepalive of the bridge is not published
+        assert_eq!(evse.request().await, (PING, vec![]));
+        evse.respond(RESPONSE_PONG, &[]).await;
+
+        registry
+            .send(ping_for(&evse.client_id, "a"), CommandOrigin::Mqtt)
+            .unwrap();
+        assert_eq!(evse.request().await, (PING, vec![]));
+        evse.respond(RESPONSE_PONG, &[]).await;
+        let response = evse.event(MqttMessageType::response_ping).await;
+        assert_eq!(response.request_id.as_deref(), Some("a"));
+    }
+
+    #[tokio::test]
+    async fn matches_late_responses_to_timed_out_requests() {
+        let registry = ConnectionRegistry::new(16);
+        let mut evse = FakeEvse::connect(
+            &registry,
+            settings(&[("evse.response_timeout_seconds", 1.into())]),
+            true,
+        )
+        .await;
+        // the keepalive of the bridge times out without an event
+        assert_eq!(evse.request().await, (PING, vec![]));
+
+        registry
+            .send(
+                MqttMessage {
+                    request_id: Some("b".to_string()),
+                    ..charge_current(&evse.client_id, 10.0)
+                },
+                CommandOrigin::Mqtt,
+            )
+            .unwrap();
+        assert_eq!(evse.request().await, (SET_PWM_PERCENT, vec![16]));
+        let timeout = evse.event(MqttMessageType::response_timeout).await;
+        assert_eq!(timeout.request_id.as_deref(), Some("b"));
+        assert_eq!(
+            timeout.rejection.unwrap().request_type,
+            MqttMessageType::request_set_charge_current
+        );
+
+        evse.respond(RESPONSE_SET_PWM_PERCENT, &[]).await;
+        let response = evse.event(MqttMessageType::response_set_pwm_percent).await;
+        assert_eq!(response.request_id.as_deref(), Some("b"));
+
+        // the late pong of the keepalive is dropped, it is read before the next response
+        evse.respond(RESPONSE_PONG, &[]).await;
+        registry
+            .send(
+                MqttMessage {
+                    request_id: Some("c".to_string()),
+                    ..charge_current(&evse.client_id, 6.0)
+                },
+                CommandOrigin::Mqtt,
+            )
+            .unwrap();
+        assert_eq!(evse.request().await, (SET_PWM_PERCENT, vec![10]));
+        evse.respond(RESPONSE_SET_PWM_PERCENT, &[]).await;
+        loop {
+            let msg = evse.events.recv().await.unwrap();
+            assert_ne!(msg.message_type, MqttMessageType::response_ping);
+            if msg.message_type == MqttMessageType::response_set_pwm_percent {
+                assert_eq!(msg.request_id.as_deref(), Some("c"));
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn replaced_connection_does_not_publish_connection_closed() {
+        let registry = ConnectionRegistry::new(16);
+        let mut old = FakeEvse::connect(&registry, settings(&[]), true).await;
+        assert_eq!(old.request().await, (PING, vec![]));
+        let mut new = FakeEvse::connect(&registry, settings(&[]), true).await;
+        let replaced = new.event(MqttMessageType::connection_replaced).await;
+        assert_eq!(
+            replaced.connection.unwrap().previous_peer_addr,
+            old.socket.local_addr().unwrap().to_string()
+        );
+
+        // however the old connection notices, the new one owns the client_id
+        drop(old.socket);
+        while let Ok(msg) = old.events.recv().await {
+            assert_ne!(msg.message_type, MqttMessageType::connection_closed);
+        }
+        assert_eq!(
+            registry.client_ids(),
+            std::collections::HashSet::from([new.client_id.clone()])
+        );
+    }
+
+    #[tokio::test]
+    async fn publishes_rejected_connections() {
+        let registry = ConnectionRegistry::new(16);
+        let reject = || settings(&[("evse.duplicate_connection", "reject".into())]);
+        let mut registered = FakeEvse::connect(&registry, reject(), true).await;
+        assert_eq!(registered.request().await, (PING, vec![]));
+        let mut refused = FakeEvse::connect(&registry, reject(), true).await;
+
+        let rejected = refused.event(MqttMessageType::connection_rejected).await;
+        let connection = rejected.connection.unwrap();
+        assert_eq!(
+            connection.peer_addr,
+            refused.socket.local_addr().unwrap().to_string()
+        );
+        assert_eq!(
+            connection.previous_peer_addr,
+            registered.socket.local_addr().unwrap().to_string()
+        );
     }
-    Ok(())
 }
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::error;
+use std::io::ErrorKind;
+
+use chrono::{DateTime, FixedOffset};
+use config::Config;
+use log::error;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use crate::protocol::MqttMessageSession;
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+/// A completed charging session as stored in the history file.
+#[derive(Serialize, Deserialize)]
+struct SessionRecord {
+    serial: String,
+    /// Name of the charging station when the session was stored, for reading the file
+    client_id: String,
+    #[serde(flatten)]
+    session: MqttMessageSession,
+}
+
+/// Completed charging sessions, appended to a file with one JSON document per line. Sessions
+/// are stored by the serial of the charging station, so they stay with it when it is renamed.
+pub struct SessionHistory {
+    path: String,
+    /// Serials of the charging stations named in `[ evse_name ]`, by name
+    serials: HashMap<String, String>,
+}
+
+impl SessionHistory {
+    pub fn from_settings(settings: &Config) -> SessionHistory {
+        let mut serials = HashMap::new();
+        for (key, name) in settings.get_table("evse_name").unwrap_or_default() {
+            if let (Some(serial), Ok(name)) = (key.strip_prefix("id_"), name.into_string()) {
+                // keys are lower case after reading the configuration
+                serials.insert(name, serial.to_uppercase());
+            }
+        }
+        SessionHistory {
+            path: settings
+                .get_string("sessions.history_file")
+                .unwrap_or_else(|_| "session_history.jsonl".to_string()),
+            serials,
+        }
+    }
+
+    /// The serial of a charging station, which is its `client_id` unless it is named.
+    fn serial<'a>(&'a self, client_id: &'a str) -> &'a str {
+        self.serials.get(client_id).map_or(client_id, String::as_str)
+    }
+
+    pub async fn append(&self, client_id: &str, session: &MqttMessageSession) -> Result<()> {
+        let mut line = serde_json::to_string(&SessionRecord {
+            serial: self.serial(client_id).to_string(),
+            client_id: client_id.to_string(),
+            session: session.clone(),
+        })?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// The sessions of a charging station started within `from` (inclusive) and `to`
+    /// (exclusive), oldest first.
+    pub async fn query(
+        &self,
+        client_id: &str,
+        from: DateTime<FixedOffset>,
+        to: DateTime<FixedOffset>,
+    ) -> Result<Vec<MqttMessageSession>> {
+        let content = match fs::read_to_string(&self.path).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+
+        let serial = self.serial(client_id);
+        let mut sessions = vec![];
+        for (number, line) in content.lines().enumerate() {
+            let record: SessionRecord = match serde_json::from_str(line) {
+                Ok(record) => record,
+                Err(err) => {
+                    // e.g. cut off by a crash while writing
+                    error!("SESSION: Skipping line {} of {}: {}", number + 1, self.path, err);
+                    continue;
+                }
+            };
+            if record.serial != serial {
+                continue;
+            }
+            match DateTime::parse_from_rfc3339(&record.session.started) {
+                Ok(started) if from <= started && started < to => sessions.push(record.session),
+                Ok(_) => {}
+                Err(err) => error!("SESSION: Skipping line {} of {}: {}", number + 1, self.path, err),
+            }
+        }
+        Ok(sessions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(started: &str, energy_kwh: f32) -> MqttMessageSession {
+        MqttMessageSession {
+            started: started.to_string(),
+            ended: None,
+            duration_seconds: 0,
+            energy_kwh,
+            peak_current: 0.0,
+        }
+    }
+
+    fn at(time: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(time).unwrap()
+    }
+
+    #[tokio::test]
+    async fn stores_sessions_by_serial() {
+        let path =
+            std::env::temp_dir().join(format!("session_history_{}.jsonl", std::process::id()));
+        let settings = |name: &str| {
+            Config::builder()
+                .set_override("sessions.history_file", path.to_str().unwrap())
+                .unwrap()
+                .set_override("evse_name.id_10BA23AB", name)
+                .unwrap()
+                .build()
+                .unwrap()
+        };
+
+        let history = SessionHistory::from_settings(&settings("garage"));
+        history
+            .append("garage", &session("2022-10-17T18:00:00+02:00", 1.0))
+            .await
+            .unwrap();
+        history
+            .append("10BA23AC", &session("2022-10-17T19:00:00+02:00", 2.0))
+            .await
+            .unwrap();
+        history
+            .append("garage", &session("2022-10-18T18:00:00+02:00", 3.0))
+            .await
+            .unwrap();
+
+        // renamed in the meantime
+        let history = SessionHistory::from_settings(&settings("carport"));
+        let from = at("2022-10-17T00:00:00+02:00");
+        let sessions = history
+            .query("carport", from, at("2022-10-18T18:00:00+02:00"))
+            .await
+            .unwrap();
+        let sessions: Vec<f32> = sessions.iter().map(|s| s.energy_kwh).collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sessions, vec![1.0]);
+    }
+}
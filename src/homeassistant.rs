@@ -0,0 +1,199 @@
+use paho_mqtt as mqtt;
+use mqtt::QOS_0;
+use serde_json::{json, Value};
+
+use crate::protocol::MqttMessage;
+use crate::topics::Topics;
+
+/// Builds the retained Home Assistant MQTT discovery configs for a newly connected charging
+/// station. All entities read the retained state topic and are only available while both the
+/// bridge and the charging station are online.
+pub fn discovery_messages(
+    discovery_prefix: &str,
+    topics: &Topics,
+    topic_status: &str,
+    new_connection: &MqttMessage,
+) -> Vec<mqtt::Message> {
+    let client_id = &new_connection.client_id;
+    let handshake = match &new_connection.handshake {
+        Some(handshake) => handshake,
+        None => return vec![],
+    };
+    let serial = &handshake.serial;
+
+    let device = json!({
+        "identifiers": [format!("dehneevse_{}", serial)],
+        "name": client_id,
+        "manufacturer": "Dehne",
+        "model": "Dehne-EVSE",
+        "serial_number": serial,
+        "sw_version": handshake.firmware_version.to_string(),
+    });
+    let availability = json!([
+        { "topic": topic_status },
+        { "topic": topics.availability_topic(client_id) },
+    ]);
+    let state_topic = topics.state_topic(client_id);
+
+    let mut entities: Vec<(&str, String, Value)> = vec![];
+    for phase in 1..=3 {
+        entities.push((
+            "sensor",
+            format!("phase{}_voltage", phase),
+            json!({
+                "name": format!("Phase {} voltage", phase),
+                "device_class": "voltage",
+                "state_class": "measurement",
+                "unit_of_measurement": "V",
+                "value_template": format!(
+                    "{{{{ value_json.measurements.phase{}_millivolts / 1000 }}}}",
+                    phase
+                ),
+            }),
+        ));
+        entities.push((
+            "sensor",
+            format!("phase{}_current", phase),
+            json!({
+                "name": format!("Phase {} current", phase),
+                "device_class": "current",
+                "state_class": "measurement",
+                "unit_of_measurement": "A",
+                "value_template": format!(
+                    "{{{{ value_json.measurements.phase{}_milliamps / 1000 }}}}",
+                    phase
+                ),
+            }),
+        ));
+    }
+    entities.push((
+        "sensor",
+        "power".to_string(),
+        json!({
+            "name": "Power",
+            "device_class": "power",
+            "state_class": "measurement",
+            "unit_of_measurement": "W",
+            "value_template": "{{ value_json.power.total_watts | round(0) }}",
+        }),
+    ));
+    entities.push((
+        "sensor",
+        "energy".to_string(),
+        json!({
+            "name": "Energy",
+            "device_class": "energy",
+            "state_class": "total_increasing",
+            "unit_of_measurement": "kWh",
+            "value_template": "{{ value_json.power.energy_kwh | round(3) }}",
+        }),
+    ));
+    entities.push((
+        "sensor",
+        "wifi_rssi".to_string(),
+        json!({
+            "name": "WiFi signal",
+            "device_class": "signal_strength",
+            "state_class": "measurement",
+            "unit_of_measurement": "dBm",
+            "entity_category": "diagnostic",
+            "value_template": "{{ value_json.measurements.wifi_rssi }}",
+        }),
+    ));
+    entities.push((
+        "sensor",
+        "uptime".to_string(),
+        json!({
+            "name": "Uptime",
+            "device_class": "duration",
+            "unit_of_measurement": "s",
+            "entity_category": "diagnostic",
+            "value_template": "{{ (value_json.measurements.uptime_milliseconds / 1000) | int }}",
+        }),
+    ));
+    entities.push((
+        "sensor",
+        "vehicle_state".to_string(),
+        json!({
+            "name": "Vehicle state",
+            "value_template": "{{ value_json.measurements.vehicle_state_meaning }}",
+        }),
+    ));
+    entities.push((
+        "sensor",
+        "pilot_voltage".to_string(),
+        json!({
+            "name": "Pilot voltage",
+            "value_template": "{{ value_json.measurements.pilot_voltage }}",
+        }),
+    ));
+    entities.push((
+        "sensor",
+        "cable_rating".to_string(),
+        json!({
+            "name": "Cable rating",
+            "value_template": "{{ value_json.measurements.proximity_pilot_amps }}",
+        }),
+    ));
+    entities.push((
+        "switch",
+        "contactor".to_string(),
+        json!({
+            "name": "Contactor",
+            "command_topic": topics.command_topic(client_id, "set_contactor_state"),
+            "payload_on": command(client_id, "set_contactor_state", json!({ "contactor_state": true })),
+            "payload_off": command(client_id, "set_contactor_state", json!({ "contactor_state": false })),
+            "state_on": "ON",
+            "state_off": "OFF",
+            "value_template": "{{ 'ON' if value_json.contactor_state else 'OFF' }}",
+        }),
+    ));
+    entities.push((
+        "number",
+        "charge_current".to_string(),
+        json!({
+            "name": "Charge current",
+            "device_class": "current",
+            "unit_of_measurement": "A",
+            "min": 0,
+            "max": handshake.limits.allowed_current(),
+            "step": 1,
+            "mode": "box",
+            "command_topic": topics.command_topic(client_id, "set_charge_current"),
+            "command_template": format!(
+                "{{\"message_type\":\"request_set_charge_current\",\"client_id\":{},\"charge_current\":{{{{ 0 if value < {} else value }}}}}}",
+                Value::from(client_id.as_str()),
+                handshake.limits.min_current
+            ),
+            "value_template": "{{ value_json.charge_current | round(0) | int }}",
+        }),
+    ));
+
+    entities
+        .into_iter()
+        .map(|(component, key, mut config)| {
+            let object_id = format!("dehneevse_{}_{}", serial, key);
+            let config_object = config.as_object_mut().unwrap();
+            config_object.insert("unique_id".to_string(), Value::from(object_id.clone()));
+            config_object.insert("object_id".to_string(), Value::from(object_id.clone()));
+            config_object.insert("device".to_string(), device.clone());
+            config_object.insert("availability".to_string(), availability.clone());
+            config_object.insert("availability_mode".to_string(), Value::from("all"));
+            config_object.insert("state_topic".to_string(), Value::from(state_topic.clone()));
+
+            mqtt::Message::new_retained(
+                format!("{}/{}/{}/config", discovery_prefix, component, object_id),
+                config.to_string(),
+                QOS_0,
+            )
+        })
+        .collect()
+}
+
+/// A complete command, accepted on the command topic of either topic layout.
+fn command(client_id: &str, command: &str, mut fields: Value) -> String {
+    let object = fields.as_object_mut().unwrap();
+    object.insert("message_type".to_string(), Value::from(format!("request_{}", command)));
+    object.insert("client_id".to_string(), Value::from(client_id));
+    fields.to_string()
+}
@@ -0,0 +1,402 @@
+use config::Config;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{
+    amps_to_pwm_percent, pwm_percent_to_amps, MqttMessage, MqttMessageMeasurements,
+    MqttMessageType, PilotVoltage, ProximityPilotAmps,
+};
+use crate::utils::evse_setting;
+
+/// Phase voltage `max_power_watts` is converted to a current with.
+const NOMINAL_VOLTAGE: f32 = 230.0;
+
+/// What happens to commands asking for more current than allowed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum LimitAction {
+    /// Send the highest allowed current instead
+    clamp,
+    /// Do not send the command at all
+    reject,
+}
+
+/// Safety limits of a charging station, configured in `[ evse ]` or `[ evse.id_<serial> ]`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EvseLimits {
+    /// Lowest charge current in ampere, apart from 0 (off)
+    pub min_current: f32,
+    /// Rating of the circuit the charging station is connected to, in ampere
+    pub max_current: f32,
+    /// Number of phases the charging station is connected to
+    pub phases: u8,
+    /// Highest power in watts the charging station may draw over all of its phases
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_power_watts: Option<f32>,
+    pub limit_action: LimitAction,
+    /// Only close the contactor while the vehicle requests to charge (6V/3V on the control
+    /// pilot) and open it as soon as the vehicle is gone (12V) or the pilot signals a fault
+    pub contactor_interlock: bool,
+}
+
+impl EvseLimits {
+    pub fn from_settings(settings: &Config, client_serial: &str) -> EvseLimits {
+        EvseLimits {
+            min_current: evse_setting(settings, client_serial, "min_current").unwrap_or(6.0),
+            max_current: evse_setting(settings, client_serial, "max_current").unwrap_or(32.0),
+            phases: evse_setting(settings, client_serial, "phases")
+                .unwrap_or(3u8)
+                .clamp(1, 3),
+            max_power_watts: evse_setting(settings, client_serial, "max_power_watts").ok(),
+            limit_action: evse_setting(settings, client_serial, "limit_action")
+                .unwrap_or(LimitAction::clamp),
+            contactor_interlock: evse_setting(settings, client_serial, "contactor_interlock")
+                .unwrap_or(true),
+        }
+    }
+
+    /// Checks a command against the configured limits and the rating of the plugged in cable.
+    /// Returns the command to send to the EVSE, possibly with a reduced charge current, or the
+    /// reason for rejecting it.
+    pub fn check(
+        &self,
+        msg: &MqttMessage,
+        measurements: Option<&MqttMessageMeasurements>,
+    ) -> Result<MqttMessage, String> {
+        let cable = measurements.map(|m| &m.proximity_pilot_amps);
+
+        let requested_current = match msg.message_type {
+            MqttMessageType::request_set_pwm_percent => match msg.pwm_percent {
+                Some(100) => return Ok(msg.clone()),
+                Some(pwm_percent) if pwm_percent_to_amps(pwm_percent) == 0.0 => {
+                    return Err(format!(
+                        "pwm_percent={} does not signal a charge current",
+                        pwm_percent
+                    ))
+                }
+                Some(pwm_percent) => pwm_percent_to_amps(pwm_percent),
+                None => return Ok(msg.clone()),
+            },
+            MqttMessageType::request_set_charge_current => match msg.charge_current {
+                Some(0.0) => return Ok(msg.clone()),
+                Some(charge_current) => charge_current,
+                None => return Ok(msg.clone()),
+            },
+            MqttMessageType::request_set_contactor_state => {
+                return match (msg.contactor_state, measurements) {
+                    (Some(true), None) => {
+                        Err("cable rating is not known yet, collect data first".to_string())
+                    }
+                    (Some(true), Some(m))
+                        if m.proximity_pilot_amps == ProximityPilotAmps::no_cable =>
+                    {
+                        Err("no cable plugged in".to_string())
+                    }
+                    (Some(true), Some(m))
+                        if self.contactor_interlock
+                            && !matches!(
+                                m.pilot_voltage,
+                                PilotVoltage::volt_6 | PilotVoltage::volt_3
+                            ) =>
+                    {
+                        Err(format!(
+                            "vehicle is not requesting to charge (pilot_voltage={:?})",
+                            m.pilot_voltage
+                        ))
+                    }
+                    _ => Ok(msg.clone()),
+                };
+            }
+            _ => return Ok(msg.clone()),
+        };
+
+        if requested_current < self.min_current {
+            return Err(format!(
+                "charge current {}A is below min_current={}A",
+                requested_current, self.min_current
+            ));
+        }
+
+        let mut allowed_current = self.allowed_current();
+        if let Some(cable_max_amps) = cable.and_then(|cable| cable.max_amps()) {
+            allowed_current = allowed_current.min(cable_max_amps as f32);
+        }
+        if requested_current <= allowed_current {
+            return Ok(msg.clone());
+        }
+
+        let reason = format!(
+            "charge current {}A exceeds the allowed {}A (max_current={}A, max_power_watts={:?} on {} phases, cable={:?})",
+            requested_current, allowed_current, self.max_current, self.max_power_watts, self.phases, cable
+        );
+        if self.limit_action == LimitAction::reject || allowed_current < self.min_current {
+            return Err(reason);
+        }
+
+        info!(
+            "EVSE: {} for {}, sending {}A instead",
+            reason, msg.client_id, allowed_current
+        );
+        let pwm_percent = amps_to_pwm_percent(allowed_current).map_err(|err| err.to_string())?;
+        Ok(MqttMessage {
+            message_type: MqttMessageType::request_set_pwm_percent,
+            pwm_percent: Some(pwm_percent),
+            charge_current: None,
+            ..msg.clone()
+        })
+    }
+
+    /// The highest charge current per phase within `max_current` and, spread over all of the
+    /// `phases`, within `max_power_watts`.
+    pub fn allowed_current(&self) -> f32 {
+        match self.max_power_watts {
+            Some(max_power_watts) => self
+                .max_current
+                .min(max_power_watts / (NOMINAL_VOLTAGE * self.phases as f32)),
+            None => self.max_current,
+        }
+    }
+
+    /// Whether the contactor has to be opened because of the given data collection, see
+    /// `contactor_interlock`.
+    pub fn must_open_contactor(&self, collect_data: &MqttMessage) -> bool {
+        self.contactor_interlock
+            && collect_data.contactor_state == Some(true)
+            && matches!(
+                collect_data.measurements.as_ref().map(|m| &m.pilot_voltage),
+                Some(PilotVoltage::volt_12 | PilotVoltage::fault)
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::MqttMessageMeasurements;
+
+    fn limits() -> EvseLimits {
+        EvseLimits {
+            max_current: 16.0,
+            ..EvseLimits::from_settings(&Config::default(), "10BA23AB")
+        }
+    }
+
+    fn measurements(
+        pilot_voltage: PilotVoltage,
+        proximity_pilot_amps: ProximityPilotAmps,
+    ) -> MqttMessageMeasurements {
+        let vehicle_state = pilot_voltage.vehicle_state();
+        MqttMessageMeasurements {
+            pilot_voltage,
+            vehicle_state,
+            vehicle_state_meaning: vehicle_state.meaning().to_string(),
+            cable_max_amps: proximity_pilot_amps.max_amps(),
+            proximity_pilot_amps,
+            phase1_millivolts: 230000,
+            phase2_millivolts: 230000,
+            phase3_millivolts: 230000,
+            phase1_milliamps: 0,
+            phase2_milliamps: 0,
+            phase3_milliamps: 0,
+            wifi_rssi: 0,
+            uptime_milliseconds: 0,
+            current_control_pilot_adc: 0,
+            current_proximity_pilot_adc: 0,
+            logging_buffer: String::new(),
+        }
+    }
+
+    fn charge_current(amps: f32) -> MqttMessage {
+        MqttMessage {
+            charge_current: Some(amps),
+            ..MqttMessage::new(
+                MqttMessageType::request_set_charge_current,
+                "evse".to_string(),
+            )
+        }
+    }
+
+    fn pwm_percent(percent: u8) -> MqttMessage {
+        MqttMessage {
+            pwm_percent: Some(percent),
+            ..MqttMessage::new(MqttMessageType::request_set_pwm_percent, "evse".to_string())
+        }
+    }
+
+    /// The duty cycle sent instead of a command exceeding the limits.
+    fn clamped(result: Result<MqttMessage, String>) -> u8 {
+        let msg = result.unwrap();
+        assert_eq!(msg.message_type, MqttMessageType::request_set_pwm_percent);
+        assert_eq!(msg.charge_current, None);
+        msg.pwm_percent.unwrap()
+    }
+
+    #[test]
+    fn passes_commands_within_the_limits() {
+        let limits = limits();
+        let msg = limits.check(&charge_current(16.0), None).unwrap();
+        assert_eq!(msg.charge_current, Some(16.0));
+        assert!(limits.check(&charge_current(0.0), None).is_ok());
+        assert_eq!(
+            limits.check(&pwm_percent(26), None).unwrap().pwm_percent,
+            Some(26)
+        );
+        assert_eq!(
+            limits.check(&pwm_percent(100), None).unwrap().pwm_percent,
+            Some(100)
+        );
+    }
+
+    #[test]
+    fn clamps_to_the_max_current() {
+        let limits = limits();
+        assert_eq!(clamped(limits.check(&charge_current(20.0), None)), 26);
+        // 32A
+        assert_eq!(clamped(limits.check(&pwm_percent(53), None)), 26);
+    }
+
+    #[test]
+    fn rejects_above_the_max_current() {
+        let limits = EvseLimits {
+            limit_action: LimitAction::reject,
+            ..limits()
+        };
+        assert!(limits.check(&charge_current(20.0), None).is_err());
+        assert!(limits.check(&pwm_percent(53), None).is_err());
+        assert!(limits.check(&charge_current(16.0), None).is_ok());
+    }
+
+    #[test]
+    fn caps_at_the_cable_rating() {
+        let limits = EvseLimits {
+            max_current: 32.0,
+            ..limits()
+        };
+        let cable = measurements(PilotVoltage::volt_6, ProximityPilotAmps::amp_13);
+        assert_eq!(
+            clamped(limits.check(&charge_current(32.0), Some(&cable))),
+            21
+        );
+        let cable = measurements(PilotVoltage::volt_6, ProximityPilotAmps::amp_32);
+        assert!(limits.check(&charge_current(32.0), Some(&cable)).is_ok());
+    }
+
+    #[test]
+    fn spreads_max_power_over_the_phases() {
+        let limits = |phases| EvseLimits {
+            max_current: 32.0,
+            phases,
+            max_power_watts: Some(11040.0),
+            ..limits()
+        };
+        assert_eq!(limits(1).allowed_current(), 32.0);
+        assert_eq!(limits(3).allowed_current(), 16.0);
+        assert!(limits(1).check(&charge_current(32.0), None).is_ok());
+        assert_eq!(clamped(limits(3).check(&charge_current(32.0), None)), 26);
+    }
+
+    #[test]
+    fn rejects_when_the_limits_are_below_the_min_current() {
+        let limits = EvseLimits {
+            max_power_watts: Some(3680.0),
+            ..limits()
+        };
+        // 5.3A on three phases
+        assert!(limits.check(&charge_current(16.0), None).is_err());
+        assert!(limits.check(&charge_current(0.0), None).is_ok());
+    }
+
+    #[test]
+    fn rejects_below_the_min_current() {
+        let limits = limits();
+        assert!(limits.check(&charge_current(5.0), None).is_err());
+        let limits = EvseLimits {
+            min_current: 8.0,
+            ..limits
+        };
+        // 7.2A
+        assert!(limits.check(&pwm_percent(12), None).is_err());
+    }
+
+    #[test]
+    fn rejects_duty_cycles_without_a_charge_current() {
+        let limits = limits();
+        for percent in [0, 1, 5, 9, 97, 98, 99] {
+            assert!(
+                limits.check(&pwm_percent(percent), None).is_err(),
+                "{}%",
+                percent
+            );
+        }
+    }
+
+    fn contactor(closed: bool) -> MqttMessage {
+        MqttMessage {
+            contactor_state: Some(closed),
+            ..MqttMessage::new(
+                MqttMessageType::request_set_contactor_state,
+                "evse".to_string(),
+            )
+        }
+    }
+
+    fn collect_data(contactor_state: bool, pilot_voltage: PilotVoltage) -> MqttMessage {
+        MqttMessage {
+            contactor_state: Some(contactor_state),
+            measurements: Some(measurements(pilot_voltage, ProximityPilotAmps::amp_32)),
+            ..MqttMessage::new(MqttMessageType::response_collect_data, "evse".to_string())
+        }
+    }
+
+    #[test]
+    fn closes_the_contactor_only_while_the_vehicle_requests_to_charge() {
+        let limits = limits();
+        for pilot_voltage in [PilotVoltage::volt_6, PilotVoltage::volt_3] {
+            let m = measurements(pilot_voltage, ProximityPilotAmps::amp_32);
+            assert!(limits.check(&contactor(true), Some(&m)).is_ok());
+        }
+        for pilot_voltage in [
+            PilotVoltage::volt_12,
+            PilotVoltage::volt_9,
+            PilotVoltage::fault,
+        ] {
+            let m = measurements(pilot_voltage, ProximityPilotAmps::amp_32);
+            assert!(limits.check(&contactor(true), Some(&m)).is_err());
+            // opening is always allowed
+            assert!(limits.check(&contactor(false), Some(&m)).is_ok());
+        }
+    }
+
+    #[test]
+    fn closes_the_contactor_without_interlock() {
+        let limits = EvseLimits {
+            contactor_interlock: false,
+            ..limits()
+        };
+        let m = measurements(PilotVoltage::volt_12, ProximityPilotAmps::amp_32);
+        assert!(limits.check(&contactor(true), Some(&m)).is_ok());
+
+        // never without a cable or before its rating is known
+        let m = measurements(PilotVoltage::volt_12, ProximityPilotAmps::no_cable);
+        assert!(limits.check(&contactor(true), Some(&m)).is_err());
+        assert!(limits.check(&contactor(true), None).is_err());
+        assert!(limits.check(&contactor(false), None).is_ok());
+    }
+
+    #[test]
+    fn opens_the_contactor_when_the_vehicle_is_gone() {
+        let limits = limits();
+        assert!(limits.must_open_contactor(&collect_data(true, PilotVoltage::volt_12)));
+        assert!(limits.must_open_contactor(&collect_data(true, PilotVoltage::fault)));
+        assert!(!limits.must_open_contactor(&collect_data(true, PilotVoltage::volt_9)));
+        assert!(!limits.must_open_contactor(&collect_data(true, PilotVoltage::volt_6)));
+        // already open
+        assert!(!limits.must_open_contactor(&collect_data(false, PilotVoltage::volt_12)));
+
+        let limits = EvseLimits {
+            contactor_interlock: false,
+            ..limits
+        };
+        assert!(!limits.must_open_contactor(&collect_data(true, PilotVoltage::volt_12)));
+    }
+}
@@ -0,0 +1,602 @@
+use std::collections::HashMap;
+use std::error;
+use std::time::Duration;
+
+use config::Config;
+use log::{error, info};
+use tokio::sync::broadcast;
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::limits::EvseLimits;
+use crate::mqtt_handler::MqttInput;
+use crate::protocol::{
+    amps_to_pwm_percent, ChargeMode, ErrorCode, MqttMessage, MqttMessageHandshake,
+    MqttMessageMeasurements, MqttMessageRejection, MqttMessageType, PilotVoltage,
+};
+use crate::registry::{CommandOrigin, ConnectionRegistry};
+use crate::solar::{Solar, SolarCharger};
+use crate::utils::{evse_setting, read_number};
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+/// A connected charging station as seen by the load balancer.
+struct Charger {
+    limits: EvseLimits,
+    priority: i64,
+    measurements: Option<MqttMessageMeasurements>,
+    /// The charge current last sent to the charging station
+    allocated: Option<f32>,
+}
+
+impl Charger {
+    fn new(handshake: MqttMessageHandshake, settings: &Config) -> Charger {
+        Charger {
+            priority: evse_setting(settings, &handshake.serial, "priority").unwrap_or(0),
+            limits: handshake.limits,
+            measurements: None,
+            allocated: None,
+        }
+    }
+
+    /// A vehicle is plugged in (states B, C and D)
+    fn is_active(&self) -> bool {
+        matches!(
+            self.measurements.as_ref().map(|m| &m.pilot_voltage),
+            Some(PilotVoltage::volt_9 | PilotVoltage::volt_6 | PilotVoltage::volt_3)
+        )
+    }
+
+    fn max_current(&self) -> f32 {
+        let cable_max_amps = self
+            .measurements
+            .as_ref()
+            .and_then(|m| m.proximity_pilot_amps.max_amps());
+        match cable_max_amps {
+            Some(cable_max_amps) => self.limits.allowed_current().min(cable_max_amps as f32),
+            None => self.limits.allowed_current(),
+        }
+    }
+
+    /// The phases the vehicle is drawing current from, or the configured number of phases
+    /// (starting with phase 1) as long as it does not draw any current.
+    fn phases_in_use(&self) -> Vec<usize> {
+        let measured: Vec<usize> = match &self.measurements {
+            Some(m) => [m.phase1_milliamps, m.phase2_milliamps, m.phase3_milliamps]
+                .iter()
+                .enumerate()
+                .filter(|(_, milliamps)| **milliamps >= 1000)
+                .map(|(phase, _)| phase)
+                .collect(),
+            None => vec![],
+        };
+        if measured.is_empty() {
+            (0..(self.limits.phases as usize).clamp(1, 3)).collect()
+        } else {
+            measured
+        }
+    }
+
+    fn phase_values(&self) -> [(f32, f32); 3] {
+        match &self.measurements {
+            Some(m) => [
+                (m.phase1_millivolts as f32 / 1000.0, m.phase1_milliamps as f32 / 1000.0),
+                (m.phase2_millivolts as f32 / 1000.0, m.phase2_milliamps as f32 / 1000.0),
+                (m.phase3_millivolts as f32 / 1000.0, m.phase3_milliamps as f32 / 1000.0),
+            ],
+            None => [(0.0, 0.0); 3],
+        }
+    }
+
+    /// Average voltage of the given phases, 230V if not measured
+    fn voltage(&self, phases: &[usize]) -> f32 {
+        let values = self.phase_values();
+        let voltage = phases.iter().map(|p| values[*p].0).sum::<f32>() / phases.len() as f32;
+        if voltage > 100.0 {
+            voltage
+        } else {
+            230.0
+        }
+    }
+
+    /// Power drawn by the vehicle in watts
+    fn power(&self) -> f32 {
+        self.phase_values().iter().map(|(volts, amps)| volts * amps).sum()
+    }
+}
+
+/// Live grid import per phase in ampere, published to MQTT by an external meter. Each phase is
+/// read from its own topic, either as a plain number or from a JSON document via a JSON pointer
+/// (e.g. `/current/l1`). Several phases may share a topic. Phases 2 and 3 without a topic, e.g.
+/// on a single phase connection, count as 0A.
+struct GridMeter {
+    main_fuse: f32,
+    stale_after: Duration,
+    fallback_current: f32,
+    topics: [Option<String>; 3],
+    json_pointers: [Option<String>; 3],
+    import: [Option<(f32, Instant)>; 3],
+}
+
+impl GridMeter {
+    fn from_settings(settings: &Config) -> Result<Option<GridMeter>> {
+        let topic = |phase: usize| {
+            settings
+                .get_string(&format!("load_balancing.grid_meter.phase{}_topic", phase))
+                .ok()
+        };
+        if topic(1).is_none() {
+            return Ok(None);
+        }
+        let json_pointer = |phase: usize| {
+            settings
+                .get_string(&format!("load_balancing.grid_meter.phase{}_json_pointer", phase))
+                .ok()
+        };
+
+        Ok(Some(GridMeter {
+            main_fuse: settings.get_float("load_balancing.grid_meter.main_fuse")? as f32,
+            stale_after: Duration::from_secs(
+                settings
+                    .get_int("load_balancing.grid_meter.stale_seconds")
+                    .unwrap_or(30) as u64,
+            ),
+            fallback_current: settings
+                .get_float("load_balancing.grid_meter.fallback_current")
+                .unwrap_or(6.0) as f32,
+            topics: [topic(1), topic(2), topic(3)],
+            json_pointers: [json_pointer(1), json_pointer(2), json_pointer(3)],
+            import: [None; 3],
+        }))
+    }
+
+    fn update(&mut self, input: &MqttInput) {
+        for phase in 0..3 {
+            if self.topics[phase].as_deref() != Some(input.topic.as_str()) {
+                continue;
+            }
+            match read_number(&input.payload, self.json_pointers[phase].as_deref()) {
+                Some(amps) => self.import[phase] = Some((amps as f32, Instant::now())),
+                None => error!(
+                    "LOAD: Could not read the grid import of phase {} from {}: {}",
+                    phase + 1,
+                    input.topic,
+                    input.payload
+                ),
+            }
+        }
+    }
+
+    /// The grid import per phase, unless any metered phase has not been updated recently.
+    fn import(&self) -> Option<[f32; 3]> {
+        let mut import = [0.0; 3];
+        for ((amps, topic), last) in import.iter_mut().zip(&self.topics).zip(self.import) {
+            match (topic, last) {
+                (None, _) => {}
+                (Some(_), Some((last_amps, updated))) if updated.elapsed() < self.stale_after => {
+                    *amps = last_amps
+                }
+                (Some(_), _) => return None,
+            }
+        }
+        Some(import)
+    }
+}
+
+/// The topics of the grid meter, which need to be subscribed to.
+pub fn input_topics(settings: &Config) -> Vec<String> {
+    if !settings.get_bool("load_balancing.enabled").unwrap_or(false) {
+        return vec![];
+    }
+    let mut topics: Vec<String> = (1..=3)
+        .filter_map(|phase| {
+            settings
+                .get_string(&format!("load_balancing.grid_meter.phase{}_topic", phase))
+                .ok()
+        })
+        .collect();
+    if let Ok(topic) = settings.get_string("load_balancing.solar.topic") {
+        topics.push(topic);
+    }
+    topics.sort();
+    topics.dedup();
+    topics
+}
+
+/// Shares the per-phase current limit of the site between all charging stations with a
+/// vehicle plugged in, issuing `request_set_charge_current` whenever the share of a
+/// charging station changes. With a grid meter, the current left under the main fuse by the
+/// rest of the household limits the share further. Charging stations in one of the solar
+/// charge modes are limited to the PV surplus.
+pub async fn handle_load_balancing(
+    registry: ConnectionRegistry,
+    mut bridge_request_rx: broadcast::Receiver<MqttMessage>,
+    evse_mqtt_tx: broadcast::Sender<MqttMessage>,
+    mut evse_mqtt_rx: broadcast::Receiver<MqttMessage>,
+    mut mqtt_input_rx: broadcast::Receiver<MqttInput>,
+    mut shutdown_rx: broadcast::Receiver<bool>,
+    settings: Config,
+) -> Result<()> {
+    let phase_limit = settings.get_float("load_balancing.phase_limit")? as f32;
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        settings
+            .get_int("load_balancing.interval_seconds")
+            .unwrap_or(10) as u64,
+    ));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut grid_meter = GridMeter::from_settings(&settings)?;
+    let mut solar = Solar::from_settings(&settings)?;
+
+    info!("LOAD: Balancing {}A per phase between all chargers", phase_limit);
+
+    let mut chargers: HashMap<String, Charger> = HashMap::new();
+    // kept across reconnects
+    let mut charge_modes: HashMap<String, ChargeMode> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            Ok(_) = shutdown_rx.recv() => {
+                break;
+            }
+            receive = evse_mqtt_rx.recv() => {
+                match receive {
+                    Err(broadcast::error::RecvError::Closed) => break,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        // connections may have come and gone among the missed messages
+                        error!("LOAD: Missed {} messages from the EVSE connections, rebuilding the chargers", skipped);
+                        let handshakes = registry.handshakes();
+                        chargers.retain(|client_id, _| {
+                            let connected = handshakes.contains_key(client_id);
+                            if let (false, Some(solar)) = (connected, solar.as_mut()) {
+                                solar.remove(client_id);
+                            }
+                            connected
+                        });
+                        for (client_id, handshake) in handshakes {
+                            charge_modes.entry(client_id.clone()).or_insert_with(|| {
+                                evse_setting(&settings, &handshake.serial, "charge_mode").unwrap_or(ChargeMode::fast)
+                            });
+                            chargers.entry(client_id).or_insert_with(|| Charger::new(handshake, &settings));
+                        }
+                        // the charge current is sent again on the next tick, measurements follow
+                        // with the next data collection
+                        chargers.values_mut().for_each(|charger| charger.allocated = None);
+                    }
+                    Ok(msg) => match msg.message_type {
+                        MqttMessageType::new_connection => {
+                            if let Some(handshake) = msg.handshake {
+                                charge_modes.entry(msg.client_id.clone()).or_insert_with(|| {
+                                    evse_setting(&settings, &handshake.serial, "charge_mode").unwrap_or(ChargeMode::fast)
+                                });
+                                chargers.insert(msg.client_id, Charger::new(handshake, &settings));
+                            }
+                        }
+                        MqttMessageType::connection_closed => {
+                            chargers.remove(&msg.client_id);
+                            if let Some(solar) = solar.as_mut() {
+                                solar.remove(&msg.client_id);
+                            }
+                        }
+                        MqttMessageType::response_collect_data => {
+                            if let Some(charger) = chargers.get_mut(&msg.client_id) {
+                                // the connection drops commands while the failsafe is engaged, the
+                                // queue is full or the limits refuse them, so unless the charging
+                                // station signals the allocated current it is sent again
+                                if let (Some(allocated), Some(pwm_percent)) = (charger.allocated, msg.pwm_percent) {
+                                    if amps_to_pwm_percent(allocated).ok() != Some(pwm_percent) {
+                                        info!("LOAD: {} signals {}% instead of {}A, sending it again", msg.client_id, pwm_percent, allocated);
+                                        charger.allocated = None;
+                                    }
+                                }
+                                charger.measurements = msg.measurements;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Ok(msg) = bridge_request_rx.recv() => {
+                if let (MqttMessageType::request_set_charge_mode, Some(charge_mode)) = (&msg.message_type, &msg.charge_mode) {
+                    let response = if *charge_mode != ChargeMode::fast && solar.is_none() {
+                        MqttMessage {
+                            rejection: Some(MqttMessageRejection {
+                                request_type: msg.message_type,
+                                reason: "no PV surplus topic configured".to_string(),
+                            }),
+                            ..MqttMessage::new(MqttMessageType::command_rejected, msg.client_id)
+                        }
+                    } else {
+                        info!("LOAD: Setting charge mode of {} to {:?}", msg.client_id, charge_mode);
+                        charge_modes.insert(msg.client_id.clone(), charge_mode.clone());
+                        MqttMessage {
+                            charge_mode: Some(charge_mode.clone()),
+                            ..MqttMessage::new(MqttMessageType::response_set_charge_mode, msg.client_id)
+                        }
+                    };
+                    evse_mqtt_tx.send(MqttMessage { request_id: msg.request_id, ..response })?;
+                } else if msg.message_type == MqttMessageType::request_set_charge_mode {
+                    evse_mqtt_tx.send(MqttMessage::error_for(&msg, ErrorCode::invalid_request, "charge_mode cannot be null".to_string()))?;
+                }
+            }
+            Ok(input) = mqtt_input_rx.recv() => {
+                if let Some(grid_meter) = grid_meter.as_mut() {
+                    grid_meter.update(&input);
+                }
+                if let Some(solar) = solar.as_mut() {
+                    solar.update(&input);
+                }
+            }
+            _ = interval.tick() => {
+                let client_ids: Vec<String> = chargers.keys().cloned().collect();
+                let mut available = [phase_limit; 3];
+                let mut max_currents: Vec<f32> = client_ids.iter().map(|id| chargers[id].max_current()).collect();
+                if let Some(grid_meter) = &grid_meter {
+                    match grid_meter.import() {
+                        Some(import) => {
+                            // the import includes what the chargers draw themselves
+                            let charging = charging_currents(&chargers);
+                            for phase in 0..3 {
+                                let household = (import[phase] - charging[phase]).max(0.0);
+                                available[phase] =
+                                    available[phase].min(grid_meter.main_fuse - household).max(0.0);
+                            }
+                        }
+                        None => {
+                            error!("LOAD: Grid meter is stale, limiting chargers to {}A", grid_meter.fallback_current);
+                            max_currents.iter_mut().for_each(|max| *max = max.min(grid_meter.fallback_current));
+                        }
+                    }
+                }
+                if let Some(solar) = solar.as_mut() {
+                    let mut solar_chargers: Vec<(usize, SolarCharger)> = vec![];
+                    for (i, client_id) in client_ids.iter().enumerate() {
+                        let charger = &chargers[client_id];
+                        let charge_mode = charge_modes.get(client_id).cloned().unwrap_or(ChargeMode::fast);
+                        if charge_mode == ChargeMode::fast || !charger.is_active() {
+                            solar.remove(client_id);
+                            continue;
+                        }
+                        let phases = charger.phases_in_use();
+                        solar_chargers.push((i, SolarCharger {
+                            client_id,
+                            charge_mode,
+                            min_current: charger.limits.min_current,
+                            phases: phases.len(),
+                            voltage: charger.voltage(&phases),
+                            power: charger.power(),
+                        }));
+                    }
+                    solar_chargers.sort_by(|(a, _), (b, _)| {
+                        chargers[&client_ids[*b]]
+                            .priority
+                            .cmp(&chargers[&client_ids[*a]].priority)
+                            .then_with(|| client_ids[*a].cmp(&client_ids[*b]))
+                    });
+                    let (indices, solar_chargers): (Vec<usize>, Vec<SolarCharger>) = solar_chargers.into_iter().unzip();
+                    for (i, limit) in indices.into_iter().zip(solar.limits(&solar_chargers)) {
+                        max_currents[i] = max_currents[i].min(limit);
+                    }
+                }
+                let allocations = distribute(available, &max_currents, &client_ids, &chargers);
+                for (client_id, current) in client_ids.into_iter().zip(allocations) {
+                    let charger = chargers.get_mut(&client_id).unwrap();
+                    if charger.allocated == Some(current) {
+                        continue;
+                    }
+                    info!("LOAD: Setting charge current of {} to {}A", client_id, current);
+                    let command = MqttMessage {
+                        charge_current: Some(current),
+                        ..MqttMessage::new(MqttMessageType::request_set_charge_current, client_id.clone())
+                    };
+                    // retried on the next tick unless sent
+                    match registry.send(command, CommandOrigin::Bridge) {
+                        Ok(()) => charger.allocated = Some(current),
+                        Err(err) => error!("LOAD: Could not set the charge current of {}: {}", client_id, err),
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The current drawn by all charging stations per phase, as of their last data collection.
+fn charging_currents(chargers: &HashMap<String, Charger>) -> [f32; 3] {
+    let mut currents = [0.0; 3];
+    for m in chargers.values().filter_map(|c| c.measurements.as_ref()) {
+        currents[0] += m.phase1_milliamps as f32 / 1000.0;
+        currents[1] += m.phase2_milliamps as f32 / 1000.0;
+        currents[2] += m.phase3_milliamps as f32 / 1000.0;
+    }
+    currents
+}
+
+/// Every charging station with a vehicle gets its minimum current first, in order of priority,
+/// and is paused (0A) if that does not fit. What is left is then handed out in steps of 1A,
+/// shared equally between charging stations of the same priority, higher priorities first.
+/// No charging station gets more than its entry in `max_currents`.
+fn distribute(
+    mut available: [f32; 3],
+    max_currents: &[f32],
+    client_ids: &[String],
+    chargers: &HashMap<String, Charger>,
+) -> Vec<f32> {
+    let chargers: Vec<&Charger> = client_ids.iter().map(|id| &chargers[id]).collect();
+    let phases: Vec<Vec<usize>> = chargers.iter().map(|c| c.phases_in_use()).collect();
+    let mut allocations = vec![0.0f32; chargers.len()];
+
+    let mut order: Vec<usize> = (0..chargers.len())
+        .filter(|i| chargers[*i].is_active())
+        .collect();
+    order.sort_by(|a, b| {
+        chargers[*b]
+            .priority
+            .cmp(&chargers[*a].priority)
+            .then_with(|| client_ids[*a].cmp(&client_ids[*b]))
+    });
+
+    for &i in &order {
+        let min_current = chargers[i].limits.min_current;
+        if max_currents[i] >= min_current
+            && phases[i].iter().all(|p| available[*p] >= min_current)
+        {
+            allocations[i] = min_current;
+            phases[i].iter().for_each(|p| available[*p] -= min_current);
+        }
+    }
+
+    let mut start = 0;
+    while start < order.len() {
+        let priority = chargers[order[start]].priority;
+        let end = order[start..]
+            .iter()
+            .position(|i| chargers[*i].priority != priority)
+            .map_or(order.len(), |n| start + n);
+        let group = &order[start..end];
+        start = end;
+
+        loop {
+            let mut increased = false;
+            for &i in group {
+                let step = (max_currents[i] - allocations[i])
+                    .min(1.0)
+                    .min(phases[i].iter().map(|p| available[*p]).fold(f32::MAX, f32::min));
+                if allocations[i] > 0.0 && step > 0.0 {
+                    allocations[i] += step;
+                    phases[i].iter().for_each(|p| available[*p] -= step);
+                    increased = true;
+                }
+            }
+            if !increased {
+                break;
+            }
+        }
+    }
+
+    allocations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ProximityPilotAmps;
+
+    /// A charging station with the default limits (6A to 32A on 3 phases).
+    fn charger(priority: i64, pilot_voltage: PilotVoltage, milliamps: [u32; 3]) -> Charger {
+        let vehicle_state = pilot_voltage.vehicle_state();
+        Charger {
+            limits: EvseLimits::from_settings(&Config::default(), "serial"),
+            priority,
+            measurements: Some(MqttMessageMeasurements {
+                pilot_voltage,
+                vehicle_state,
+                vehicle_state_meaning: vehicle_state.meaning().to_string(),
+                proximity_pilot_amps: ProximityPilotAmps::amp_32,
+                cable_max_amps: Some(32),
+                phase1_millivolts: 230000,
+                phase2_millivolts: 230000,
+                phase3_millivolts: 230000,
+                phase1_milliamps: milliamps[0],
+                phase2_milliamps: milliamps[1],
+                phase3_milliamps: milliamps[2],
+                wifi_rssi: 0,
+                uptime_milliseconds: 0,
+                current_control_pilot_adc: 0,
+                current_proximity_pilot_adc: 0,
+                logging_buffer: String::new(),
+            }),
+            allocated: None,
+        }
+    }
+
+    fn run(available: [f32; 3], chargers: Vec<(&str, Charger)>) -> Vec<f32> {
+        let client_ids: Vec<String> = chargers.iter().map(|(id, _)| id.to_string()).collect();
+        let max_currents: Vec<f32> = chargers.iter().map(|(_, c)| c.max_current()).collect();
+        let chargers: HashMap<String, Charger> = chargers
+            .into_iter()
+            .map(|(id, c)| (id.to_string(), c))
+            .collect();
+        distribute(available, &max_currents, &client_ids, &chargers)
+    }
+
+    #[test]
+    fn shares_equally_within_a_priority() {
+        let allocations = run(
+            [20.0; 3],
+            vec![
+                ("a", charger(0, PilotVoltage::volt_6, [0; 3])),
+                ("b", charger(0, PilotVoltage::volt_6, [0; 3])),
+            ],
+        );
+        assert_eq!(allocations, vec![10.0, 10.0]);
+    }
+
+    #[test]
+    fn serves_higher_priorities_first() {
+        let allocations = run(
+            [30.0; 3],
+            vec![
+                ("a", charger(0, PilotVoltage::volt_6, [0; 3])),
+                ("b", charger(1, PilotVoltage::volt_6, [0; 3])),
+            ],
+        );
+        assert_eq!(allocations, vec![6.0, 24.0]);
+    }
+
+    #[test]
+    fn pauses_chargers_without_their_minimum_current() {
+        let allocations = run(
+            [10.0; 3],
+            vec![
+                ("a", charger(0, PilotVoltage::volt_6, [0; 3])),
+                ("b", charger(1, PilotVoltage::volt_6, [0; 3])),
+            ],
+        );
+        assert_eq!(allocations, vec![0.0, 10.0]);
+    }
+
+    #[test]
+    fn skips_chargers_without_a_vehicle() {
+        let allocations = run(
+            [20.0; 3],
+            vec![
+                ("a", charger(0, PilotVoltage::volt_12, [0; 3])),
+                ("b", charger(0, PilotVoltage::volt_6, [0; 3])),
+            ],
+        );
+        assert_eq!(allocations, vec![0.0, 20.0]);
+    }
+
+    #[test]
+    fn limits_each_phase_separately() {
+        // a charges on phase 1 only, so b is limited by what a leaves on phase 1
+        let allocations = run(
+            [16.0, 32.0, 32.0],
+            vec![
+                ("a", charger(0, PilotVoltage::volt_6, [8000, 0, 0])),
+                ("b", charger(0, PilotVoltage::volt_6, [8000, 8000, 8000])),
+            ],
+        );
+        assert_eq!(allocations, vec![8.0, 8.0]);
+
+        // single phase chargers on different phases do not compete
+        let allocations = run(
+            [16.0, 16.0, 16.0],
+            vec![
+                ("a", charger(0, PilotVoltage::volt_6, [8000, 0, 0])),
+                ("b", charger(0, PilotVoltage::volt_6, [0, 8000, 0])),
+            ],
+        );
+        assert_eq!(allocations, vec![16.0, 16.0]);
+    }
+
+    #[test]
+    fn respects_the_maximum_current() {
+        let allocations = run(
+            [63.0; 3],
+            vec![("a", charger(0, PilotVoltage::volt_6, [0; 3]))],
+        );
+        assert_eq!(allocations, vec![32.0]);
+    }
+}
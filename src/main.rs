@@ -5,16 +5,32 @@ use config::Config;
 use env_logger::{Builder, Target};
 use log::{info, error, LevelFilter};
 use tokio::net::TcpListener;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, watch};
 use utils::{USizeCountDownLatch, CountDownLatch};
 
 use crate::evse_handler::handle_evse;
-use crate::mqtt_handler::handle_mqtt;
+use crate::load_balancer::handle_load_balancing;
+use crate::mqtt_handler::{handle_mqtt, Router};
+use crate::planner::handle_planner;
+use crate::registry::{ConnectionRegistry, DuplicatePolicy};
+use crate::schedule::Schedule;
+use crate::session::handle_sessions;
+use crate::topics::Topics;
 
 mod cli;
 mod evse_handler;
+mod history;
+mod homeassistant;
+mod limits;
+mod load_balancer;
 mod mqtt_handler;
+mod planner;
 mod protocol;
+mod registry;
+mod schedule;
+mod session;
+mod solar;
+mod topics;
 mod utils;
 
 /*
@@ -43,15 +59,51 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .set_default("mqtt.broker", args.mqtt_broker)?
         .set_default("mqtt.topic_subscribe", args.mqtt_topic_subscribe)?
         .set_default("mqtt.topic_publish", args.mqtt_topic_publish)?
+        .set_default("mqtt.topic_status", args.mqtt_topic_status)?
         .add_source(config::File::with_name(&args.configuration_file).required(false))
         .add_source(config::Environment::with_prefix("DEHNEEVSE").separator("_"))
         .build()?;
 
+    // read again on every connection, where an invalid value would refuse the charging station
+    // on each of its reconnects
+    for client_serial in std::iter::once(String::new()).chain(utils::configured_serials(&settings)) {
+        let section = if client_serial.is_empty() {
+            "evse".to_string()
+        } else {
+            format!("evse.id_{}", client_serial)
+        };
+        Schedule::from_settings(&settings, &client_serial)
+            .map_err(|err| format!("Invalid schedule in [ {} ]: {}", section, err))?;
+        DuplicatePolicy::from_settings(&settings, &client_serial)
+            .map_err(|err| format!("Invalid duplicate_connection in [ {} ]: {}", section, err))?;
+    }
+
+    let topics = Topics::from_settings(&settings)?;
+    let homeassistant_discovery_prefix = if settings.get_bool("homeassistant.enabled").unwrap_or(false) {
+        Some(
+            settings
+                .get_string("homeassistant.discovery_prefix")
+                .unwrap_or_else(|_| "homeassistant".to_string()),
+        )
+    } else {
+        None
+    };
+    let mut input_topics = load_balancer::input_topics(&settings);
+    input_topics.extend(planner::input_topics(&settings));
+    input_topics.sort();
+    input_topics.dedup();
+
     // Communication channels between threads:
     // EVSE connections -> MQTT
     let (evse_mqtt_tx, evse_mqtt_rx) = broadcast::channel(32);
-    // MQTT -> EVSE connections
-    let (mqtt_evse_tx, mqtt_evse_rx) = broadcast::channel(32);
+    // MQTT, load balancing, planner -> EVSE connections
+    let registry = ConnectionRegistry::new(32);
+    // MQTT -> requests answered by load balancing, planner, sessions
+    let (bridge_request_tx, bridge_request_rx) = broadcast::channel(32);
+    // MQTT broker connection state -> EVSE connections
+    let (broker_connected_tx, broker_connected_rx) = watch::channel(false);
+    // MQTT input topics -> load balancing, planner
+    let (mqtt_input_tx, mqtt_input_rx) = broadcast::channel(32);
     // Shutdown
     let (shutdown_tx, _shutdown_rx) = broadcast::channel::<bool>(32);
 
@@ -86,7 +138,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         settings.get_int("evse.bind_port")?
     );
 
-    let mqtt_evse_tx_clone = mqtt_evse_tx.clone();
+    let router = Router::new(registry.clone(), bridge_request_tx, &settings);
     let evse_mqtt_rx_clone = evse_mqtt_rx.resubscribe();
     let shutdown_rx_clone = shutdown_tx.subscribe();
     let shutdown_tx_clone = shutdown_tx.clone();
@@ -97,11 +149,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
         
         handle_mqtt(
             settings_clone.get_string("mqtt.broker").unwrap(),
-            settings_clone.get_string("mqtt.topic_subscribe").unwrap(),
-            settings_clone.get_string("mqtt.topic_publish").unwrap(),
-            mqtt_evse_tx_clone,
+            topics,
+            settings_clone.get_string("mqtt.topic_status").unwrap(),
+            homeassistant_discovery_prefix,
+            router,
             evse_mqtt_rx_clone,
+            input_topics,
+            mqtt_input_tx,
             shutdown_rx_clone,
+            broker_connected_tx,
         ).await.unwrap_or_else(|err| {
             shutdown_tx_clone.send(true).unwrap();
             error!("MQTT: handling failed: {}", err);
@@ -110,14 +166,105 @@ async fn main() -> Result<(), Box<dyn Error>> {
         active_threads_clone.count_down();
     });
 
+    if settings.get_bool("load_balancing.enabled").unwrap_or(false) {
+        let registry_clone = registry.clone();
+        let bridge_request_rx_clone = bridge_request_rx.resubscribe();
+        let evse_mqtt_tx_clone = evse_mqtt_tx.clone();
+        let evse_mqtt_rx_clone = evse_mqtt_rx.resubscribe();
+        let mqtt_input_rx_clone = mqtt_input_rx.resubscribe();
+        let shutdown_rx_clone = shutdown_tx.subscribe();
+        let shutdown_tx_clone = shutdown_tx.clone();
+        let settings_clone = settings.clone();
+        let active_threads_clone = active_threads.clone();
+        tokio::spawn(async move {
+            active_threads_clone.count_up();
+
+            handle_load_balancing(
+                registry_clone,
+                bridge_request_rx_clone,
+                evse_mqtt_tx_clone,
+                evse_mqtt_rx_clone,
+                mqtt_input_rx_clone,
+                shutdown_rx_clone,
+                settings_clone,
+            )
+            .await
+            .unwrap_or_else(|err| {
+                shutdown_tx_clone.send(true).unwrap();
+                error!("LOAD: load balancing failed: {}", err);
+            });
+
+            active_threads_clone.count_down();
+        });
+    }
+
+    let bridge_request_rx_clone = bridge_request_rx.resubscribe();
+    let evse_mqtt_tx_clone = evse_mqtt_tx.clone();
+    let evse_mqtt_rx_clone = evse_mqtt_rx.resubscribe();
+    let shutdown_rx_clone = shutdown_tx.subscribe();
+    let shutdown_tx_clone = shutdown_tx.clone();
+    let settings_clone = settings.clone();
+    let active_threads_clone = active_threads.clone();
+    tokio::spawn(async move {
+        active_threads_clone.count_up();
+
+        handle_sessions(
+            bridge_request_rx_clone,
+            evse_mqtt_tx_clone,
+            evse_mqtt_rx_clone,
+            shutdown_rx_clone,
+            settings_clone,
+        )
+        .await
+        .unwrap_or_else(|err| {
+            shutdown_tx_clone.send(true).unwrap();
+            error!("SESSION: session tracking failed: {}", err);
+        });
+
+        active_threads_clone.count_down();
+    });
+
+    if settings.get_string("tariff.price_topic").is_ok() {
+        let registry_clone = registry.clone();
+        let bridge_request_rx_clone = bridge_request_rx.resubscribe();
+        let evse_mqtt_tx_clone = evse_mqtt_tx.clone();
+        let evse_mqtt_rx_clone = evse_mqtt_rx.resubscribe();
+        let mqtt_input_rx_clone = mqtt_input_rx.resubscribe();
+        let shutdown_rx_clone = shutdown_tx.subscribe();
+        let shutdown_tx_clone = shutdown_tx.clone();
+        let settings_clone = settings.clone();
+        let active_threads_clone = active_threads.clone();
+        tokio::spawn(async move {
+            active_threads_clone.count_up();
+
+            handle_planner(
+                registry_clone,
+                bridge_request_rx_clone,
+                evse_mqtt_tx_clone,
+                evse_mqtt_rx_clone,
+                mqtt_input_rx_clone,
+                shutdown_rx_clone,
+                settings_clone,
+            )
+            .await
+            .unwrap_or_else(|err| {
+                shutdown_tx_clone.send(true).unwrap();
+                error!("PLAN: charge planning failed: {}", err);
+            });
+
+            active_threads_clone.count_down();
+        });
+    }
+
     let mut shutdown_rx = shutdown_tx.subscribe();
     loop {
         tokio::select! {
             accept = listener.accept() => {
                 let (socket, peer_addr) = accept.unwrap();
-                let mqtt_evse_rx_clone = mqtt_evse_rx.resubscribe();
+                let registry_clone = registry.clone();
                 let evse_mqtt_tx_clone = evse_mqtt_tx.clone();
                 let shutdown_rx_clone = shutdown_tx.subscribe();
+                let broker_connected_rx_clone = broker_connected_rx.clone();
                 let active_threads_clone = active_threads.clone();
                 let settings_clone = settings.clone();
                 tokio::spawn(async move {
@@ -125,11 +272,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     active_threads_clone.count_up();
 
                     handle_evse(
-                        mqtt_evse_rx_clone,
+                        registry_clone,
                         evse_mqtt_tx_clone,
                         socket,
                         peer_addr,
                         shutdown_rx_clone,
+                        broker_connected_rx_clone,
                         settings_clone.clone()
                     )
                     .await.unwrap_or_else(|err| {
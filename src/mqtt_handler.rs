@@ -1,22 +1,113 @@
+use config::Config;
 use log::{error, info};
 use mqtt::QOS_0;
 use paho_mqtt as mqtt;
 use paho_mqtt::AsyncClient;
+use std::collections::{HashSet, VecDeque};
 use std::error;
 use std::time::Duration;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, watch};
 
-use crate::protocol::MqttMessage;
+use crate::homeassistant::discovery_messages;
+use crate::protocol::{ErrorCode, MqttMessage, MqttMessageRejection, MqttMessageType};
+use crate::registry::{CommandOrigin, ConnectionRegistry, RouteError};
+use crate::topics::Topics;
 
 type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
 
+/// A message received on one of the additional input topics (e.g. a grid meter), forwarded as is.
+#[derive(Debug, Clone)]
+pub struct MqttInput {
+    pub topic: String,
+    pub payload: String,
+}
+
+/// Delivers commands received from MQTT to the connection of their charging station, or to the
+/// tasks of the bridge for requests answered by the bridge itself.
+pub struct Router {
+    registry: ConnectionRegistry,
+    bridge_request_tx: broadcast::Sender<MqttMessage>,
+    load_balancing: bool,
+    planner: bool,
+}
+
+impl Router {
+    pub fn new(
+        registry: ConnectionRegistry,
+        bridge_request_tx: broadcast::Sender<MqttMessage>,
+        settings: &Config,
+    ) -> Router {
+        Router {
+            registry,
+            bridge_request_tx,
+            load_balancing: settings.get_bool("load_balancing.enabled").unwrap_or(false),
+            planner: settings.get_string("tariff.price_topic").is_ok(),
+        }
+    }
+
+    /// Returns the reply to publish instead if the command cannot be delivered.
+    fn route(&self, msg: MqttMessage) -> Option<MqttMessage> {
+        let not_handled =
+            |msg: &MqttMessage, reason: &str| MqttMessage::error_for(msg, ErrorCode::not_handled, reason.to_string());
+        match msg.message_type {
+            MqttMessageType::request_set_charge_mode if !self.load_balancing => {
+                Some(not_handled(&msg, "load balancing is not enabled"))
+            }
+            MqttMessageType::request_set_charge_plan if !self.planner => Some(not_handled(
+                &msg,
+                "charge planning is not enabled, tariff.price_topic is not set",
+            )),
+            // answered without the charging station being connected
+            MqttMessageType::request_set_charge_mode
+            | MqttMessageType::request_set_charge_plan
+            | MqttMessageType::request_session_history => self
+                .bridge_request_tx
+                .send(msg)
+                .err()
+                .map(|err| not_handled(&err.0, "no task answers this request")),
+            _ => match self.registry.send(msg.clone(), CommandOrigin::Mqtt) {
+                Ok(()) => None,
+                Err(RouteError::NotConnected) => Some(MqttMessage::error_for(
+                    &msg,
+                    ErrorCode::not_connected,
+                    format!("charging station {} is not connected", msg.client_id),
+                )),
+                Err(err @ RouteError::Full) => Some(MqttMessage {
+                    request_id: msg.request_id,
+                    rejection: Some(MqttMessageRejection {
+                        request_type: msg.message_type,
+                        reason: err.to_string(),
+                    }),
+                    ..MqttMessage::new(MqttMessageType::command_dropped, msg.client_id)
+                }),
+            },
+        }
+    }
+}
+
+/// Queues a reply to a message received from MQTT.
+fn publish_reply(publish_queue: &mut VecDeque<mqtt::Message>, topics: &Topics, msg: MqttMessage) {
+    match serde_json::to_string(&msg) {
+        Err(err) => error!("MQTT: Could not serialize message to JSON: {:?}: {}", &msg, err),
+        Ok(json) => {
+            info!("MQTT: publishing reply: {}", json);
+            publish_queue.push_back(mqtt::Message::new(topics.publish_topic(&msg), json, QOS_0));
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_mqtt(
     broker: String,
-    topic_subscribe: String,
-    topic_publish: String,
-    mqtt_evse_tx: broadcast::Sender<MqttMessage>,
+    topics: Topics,
+    topic_status: String,
+    homeassistant_discovery_prefix: Option<String>,
+    router: Router,
     mut evse_mqtt_rx: broadcast::Receiver<MqttMessage>,
+    input_topics: Vec<String>,
+    mqtt_input_tx: broadcast::Sender<MqttInput>,
     mut shutdown_rx: broadcast::Receiver<bool>,
+    broker_connected_tx: watch::Sender<bool>,
 ) -> Result<()> {
     let create_opts = mqtt::CreateOptionsBuilder::new()
         .server_uri(&broker)
@@ -24,9 +115,11 @@ pub async fn handle_mqtt(
         .finalize();
     let mut cli = AsyncClient::new(create_opts)?;
 
+    // the broker announces us as offline if we vanish without disconnecting
     let conn_opts = mqtt::ConnectOptionsBuilder::new()
         .keep_alive_interval(Duration::from_secs(20))
         .clean_session(true)
+        .will_message(mqtt::Message::new_retained(&topic_status, "offline", QOS_0))
         .finalize();
 
     let st = cli.get_stream(10);
@@ -35,12 +128,22 @@ pub async fn handle_mqtt(
     let mut subscribed = true;
     let mut need_reconnect = false;
     let mut need_sleep = false;
-    let mut publish_msg: Option<mqtt::Message> = None;
+    let mut publish_queue: VecDeque<mqtt::Message> = VecDeque::new();
+    // charging stations currently announced as online
+    let mut online: HashSet<String> = HashSet::new();
+    let mut subscriptions = vec![topics.subscription(), topics.availability_subscription()];
+    subscriptions.extend(input_topics.iter().cloned());
+    let subscriptions_qos = vec![mqtt::QOS_0; subscriptions.len()];
 
     loop {
         let connected_ok = connected && !need_reconnect && subscribed && !need_sleep;
+        broker_connected_tx.send_if_modified(|broker_connected| {
+            let modified = *broker_connected != connected_ok;
+            *broker_connected = connected_ok;
+            modified
+        });
 
-        let publish_to_mqtt = async { cli.publish(publish_msg.clone().unwrap()).await };
+        let publish_to_mqtt = async { cli.publish(publish_queue.front().unwrap().clone()).await };
 
         tokio::select! {
             connect = cli.connect(conn_opts.clone()), if !connected && !need_sleep => {
@@ -58,15 +161,37 @@ pub async fn handle_mqtt(
                     },
                 }
             }
-            publish = publish_to_mqtt, if publish_msg.is_some() => {
+            publish = publish_to_mqtt, if !publish_queue.is_empty() => {
                 if let Err(err) = publish {
                     error!("Could not send json to MQTT: {}", err);
                 }
-                publish_msg = None;
+                publish_queue.pop_front();
             }
             _ = tokio::time::sleep(Duration::from_secs(10)), if need_sleep => {need_sleep = false}
             Ok(_) = shutdown_rx.recv() => {
                 info!("MQTT: Disconnecting due to shutdown");
+                if connected_ok {
+                    for client_id in online.drain() {
+                        let offline = mqtt::Message::new_retained(
+                            topics.availability_topic(&client_id),
+                            "offline",
+                            QOS_0,
+                        );
+                        if let Err(err) = cli.publish(offline).await {
+                            error!("MQTT: Could not publish offline state of {}: {}", client_id, err);
+                        }
+                    }
+                    let offline = mqtt::Message::new_retained(&topic_status, "offline", QOS_0);
+                    if let Err(err) = cli.publish(offline).await {
+                        error!("MQTT: Could not publish offline state of the bridge: {}", err);
+                    }
+                    let disconnect_options = mqtt::DisconnectOptionsBuilder::new()
+                        .timeout(Duration::from_secs(5))
+                        .finalize();
+                    if let Err(err) = cli.disconnect(disconnect_options).await {
+                        error!("MQTT: Could not disconnect from broker: {}", err);
+                    }
+                }
                 break;
             }
             connect = cli.reconnect(), if need_reconnect && !need_sleep => {
@@ -82,14 +207,15 @@ pub async fn handle_mqtt(
                     },
                 }
             }
-            subscribe = cli.subscribe(&topic_subscribe, mqtt::QOS_0), if !subscribed && !need_sleep => {
+            subscribe = cli.subscribe_many(&subscriptions, &subscriptions_qos), if !subscribed && !need_sleep => {
                 match subscribe {
                     Ok(_) => {
-                        info!("MQTT: Subscribed to {}", &topic_subscribe);
+                        info!("MQTT: Subscribed to {}", subscriptions.join(", "));
                         subscribed = true;
+                        publish_queue.push_back(mqtt::Message::new_retained(&topic_status, "online", QOS_0));
                     }
                     Err(err) => {
-                        error!("MQTT: Could not subscribe to topic {}: {}", &topic_subscribe, err);
+                        error!("MQTT: Could not subscribe to topics {}: {}", subscriptions.join(", "), err);
                         need_reconnect = true;
                         need_sleep = true;
                         let disconnect_options = mqtt::DisconnectOptionsBuilder::new()
@@ -99,29 +225,85 @@ pub async fn handle_mqtt(
                     },
                 }
             }
-            receive = evse_mqtt_rx.recv(), if connected_ok && publish_msg.is_none() => {if let Ok(msg) = receive {
+            receive = evse_mqtt_rx.recv(), if connected_ok && publish_queue.is_empty() => {if let Err(broadcast::error::RecvError::Lagged(skipped)) = receive {
+                // connection_closed or new_connection may have been among the missed messages
+                error!("MQTT: Missed {} messages from the EVSE connections, re-syncing availability", skipped);
+                let connected = router.registry.client_ids();
+                for client_id in online.symmetric_difference(&connected) {
+                    let availability = if connected.contains(client_id) { "online" } else { "offline" };
+                    publish_queue.push_back(mqtt::Message::new_retained(
+                        topics.availability_topic(client_id), availability, QOS_0));
+                }
+                online = connected;
+            } else if let Ok(msg) = receive {
                 match serde_json::to_string(&msg) {
                     Err(err) => error!("MQTT: Could not serialize message to JSON: {:?}: {}", &msg, err),
                     Ok(json) => {
                         info!("MQTT: publishing msg from EVSE: {}", json);
-                        publish_msg = Some(mqtt::Message::new(&topic_publish,json, QOS_0));
+                        let topic = topics.publish_topic(&msg);
+                        let is_state_topic = topic == topics.state_topic(&msg.client_id);
+                        if is_state_topic {
+                            publish_queue.push_back(mqtt::Message::new_retained(topic, json.clone(), QOS_0));
+                        } else {
+                            publish_queue.push_back(mqtt::Message::new(topic, json.clone(), QOS_0));
+                        }
+
+                        // retained messages for consumers joining later
+                        match msg.message_type {
+                            MqttMessageType::new_connection => {
+                                online.insert(msg.client_id.clone());
+                                publish_queue.push_back(mqtt::Message::new_retained(
+                                    topics.availability_topic(&msg.client_id), "online", QOS_0));
+                                if let Some(discovery_prefix) = &homeassistant_discovery_prefix {
+                                    publish_queue.extend(discovery_messages(discovery_prefix, &topics, &topic_status, &msg));
+                                }
+                            }
+                            MqttMessageType::connection_closed => {
+                                online.remove(&msg.client_id);
+                                publish_queue.push_back(mqtt::Message::new_retained(
+                                    topics.availability_topic(&msg.client_id), "offline", QOS_0));
+                            }
+                            MqttMessageType::response_collect_data if !is_state_topic => {
+                                publish_queue.push_back(mqtt::Message::new_retained(
+                                    topics.state_topic(&msg.client_id), json, QOS_0));
+                            }
+                            _ => {}
+                        }
                     }
                 }
             }}
             receive = st.recv(), if connected_ok => {
                 match receive {
+                    Ok(Some(msg)) if input_topics.iter().any(|topic| topic == msg.topic()) => {
+                        // nobody listening is fine
+                        let _ = mqtt_input_tx.send(MqttInput {
+                            topic: msg.topic().to_string(),
+                            payload: msg.payload_str().into_owned(),
+                        });
+                    }
+                    Ok(Some(msg)) if topics.availability_client_id(msg.topic()).is_some() => {
+                        // left behind by a bridge which was killed, the broker only announces the
+                        // bridge itself as offline then
+                        let client_id = topics.availability_client_id(msg.topic()).unwrap();
+                        if msg.payload_str() == "online" && !router.registry.client_ids().contains(client_id) {
+                            info!("MQTT: {} is not connected, clearing its availability", client_id);
+                            publish_queue.push_back(mqtt::Message::new_retained(msg.topic(), "offline", QOS_0));
+                        }
+                    }
                     Ok(Some(msg)) => {
                         let payload_string = msg.payload_str().into_owned();
-                        match serde_json::from_str::<MqttMessage>(&payload_string) {
+                        match topics.parse(msg.topic(), &payload_string) {
                             Ok(json) => {
                                 info!("MQTT: message received {}", payload_string);
-                                mqtt_evse_tx.send(json).unwrap_or_else(|err| {
-                                    error!("Could not forward message to EVSE-side: {}", err);
-                                    0
-                                });
+                                if let Some(reply) = router.route(json) {
+                                    error!("MQTT: Could not deliver message {}", payload_string);
+                                    publish_reply(&mut publish_queue, &topics, reply);
+                                }
                             }
                             Err(err) => {
                                 error!("MQTT: Could not parse received mqtt-message {}. Error={}", &payload_string, err);
+                                let error = topics.parse_error(msg.topic(), &payload_string, err.to_string());
+                                publish_reply(&mut publish_queue, &topics, error);
                             }
                         }
                     }
@@ -147,3 +329,53 @@ pub async fn handle_mqtt(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ChargeMode;
+
+    fn router(settings: &Config) -> (Router, broadcast::Receiver<MqttMessage>) {
+        let (bridge_request_tx, bridge_request_rx) = broadcast::channel(4);
+        let router = Router::new(ConnectionRegistry::new(4), bridge_request_tx, settings);
+        (router, bridge_request_rx)
+    }
+
+    fn set_charge_mode() -> MqttMessage {
+        MqttMessage {
+            request_id: Some("1".to_string()),
+            charge_mode: Some(ChargeMode::solar_only),
+            ..MqttMessage::new(MqttMessageType::request_set_charge_mode, "evse".to_string())
+        }
+    }
+
+    #[test]
+    fn answers_set_charge_mode_without_load_balancing() {
+        let (router, _bridge_request_rx) = router(&Config::default());
+        let reply = router.route(set_charge_mode()).unwrap();
+        assert_eq!(reply.message_type, MqttMessageType::error);
+        assert_eq!(reply.request_id.as_deref(), Some("1"));
+        assert_eq!(reply.error.unwrap().code, ErrorCode::not_handled);
+    }
+
+    #[test]
+    fn forwards_set_charge_mode_to_load_balancing() {
+        let settings = Config::builder()
+            .set_override("load_balancing.enabled", true)
+            .unwrap()
+            .build()
+            .unwrap();
+        let (router, mut bridge_request_rx) = router(&settings);
+        assert!(router.route(set_charge_mode()).is_none());
+        let forwarded = bridge_request_rx.try_recv().unwrap();
+        assert_eq!(forwarded.message_type, MqttMessageType::request_set_charge_mode);
+    }
+
+    #[test]
+    fn answers_commands_for_unknown_charging_stations() {
+        let (router, _bridge_request_rx) = router(&Config::default());
+        let ping = MqttMessage::new(MqttMessageType::request_ping, "evse".to_string());
+        let reply = router.route(ping).unwrap();
+        assert_eq!(reply.error.unwrap().code, ErrorCode::not_connected);
+    }
+}
@@ -0,0 +1,524 @@
+use std::collections::HashMap;
+use std::error;
+use std::time::Duration;
+
+use chrono::{DateTime, Local, Utc};
+use config::Config;
+use log::{error, info};
+use serde_json::Value;
+use tokio::sync::broadcast;
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::limits::EvseLimits;
+use crate::mqtt_handler::MqttInput;
+use crate::protocol::{
+    ErrorCode, MqttMessage, MqttMessageChargePlan, MqttMessageMeasurements, MqttMessageRejection,
+    MqttMessageType, PilotVoltage,
+};
+use crate::registry::{CommandOrigin, ConnectionRegistry};
+use crate::utils::energy_kwh;
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+/// A connected charging station as seen by the planner.
+struct Charger {
+    limits: EvseLimits,
+    measurements: Option<MqttMessageMeasurements>,
+    measured: Option<Instant>,
+    /// Whether the planner last started (true) or stopped (false) charging
+    charging: Option<bool>,
+}
+
+impl Charger {
+    /// Power drawn by the vehicle in watts
+    fn power(&self) -> f32 {
+        self.measurements.as_ref().map_or(0.0, |m| m.power())
+    }
+
+    fn max_current(&self) -> f32 {
+        match self
+            .measurements
+            .as_ref()
+            .and_then(|m| m.proximity_pilot_amps.max_amps())
+        {
+            Some(cable_max_amps) => self.limits.allowed_current().min(cable_max_amps as f32),
+            None => self.limits.allowed_current(),
+        }
+    }
+
+    /// Energy charged per hour in kWh, as measured while charging or estimated from the highest
+    /// allowed current otherwise.
+    fn energy_per_hour(&self) -> f32 {
+        let power = self.power();
+        if power > 100.0 {
+            return power / 1000.0;
+        }
+        let voltage = match &self.measurements {
+            Some(m) if m.phase1_millivolts > 100_000 => m.phase1_millivolts as f32 / 1000.0,
+            _ => 230.0,
+        };
+        self.max_current() * self.limits.phases as f32 * voltage / 1000.0
+    }
+}
+
+/// A charging target of a charging station, kept across reconnects until it is reached, the
+/// departure time has passed or the vehicle is unplugged.
+struct Plan {
+    energy_kwh: f32,
+    departure: DateTime<Utc>,
+    charged_kwh: f32,
+    hours: Vec<DateTime<Utc>>,
+}
+
+impl Plan {
+    fn to_message(&self) -> MqttMessageChargePlan {
+        MqttMessageChargePlan {
+            energy_kwh: self.energy_kwh,
+            departure: self.departure.with_timezone(&Local).to_rfc3339(),
+            hours: self
+                .hours
+                .iter()
+                .map(|hour| hour.with_timezone(&Local).to_rfc3339())
+                .collect(),
+        }
+    }
+}
+
+/// The topic of the price list, which needs to be subscribed to.
+pub fn input_topics(settings: &Config) -> Vec<String> {
+    settings.get_string("tariff.price_topic").into_iter().collect()
+}
+
+/// Reads an hourly price list like `[{"start": "2022-10-18T01:00:00+02:00", "price": 0.21}, ...]`,
+/// optionally at the given JSON pointer of the payload.
+fn read_prices(payload: &str, json_pointer: Option<&str>) -> Option<Vec<(DateTime<Utc>, f64)>> {
+    let json: Value = serde_json::from_str(payload).ok()?;
+    let list = match json_pointer {
+        Some(pointer) => json.pointer(pointer)?,
+        None => &json,
+    };
+    list.as_array()?
+        .iter()
+        .map(|entry| {
+            let start = DateTime::parse_from_rfc3339(entry.get("start")?.as_str()?).ok()?;
+            Some((start.with_timezone(&Utc), entry.get("price")?.as_f64()?))
+        })
+        .collect()
+}
+
+/// The cheapest hours starting before the departure, as many as needed (the current hour
+/// counts as a whole one), in order of time.
+fn cheapest_hours(
+    prices: &[(DateTime<Utc>, f64)],
+    now: DateTime<Utc>,
+    departure: DateTime<Utc>,
+    hours_needed: usize,
+) -> Vec<DateTime<Utc>> {
+    let hour = chrono::Duration::hours(1);
+    let mut candidates: Vec<&(DateTime<Utc>, f64)> = prices
+        .iter()
+        .filter(|(start, _)| *start + hour > now && *start < departure)
+        .collect();
+    candidates.sort_by(|(a_start, a_price), (b_start, b_price)| {
+        a_price.total_cmp(b_price).then_with(|| a_start.cmp(b_start))
+    });
+    let mut hours: Vec<DateTime<Utc>> = candidates
+        .into_iter()
+        .take(hours_needed)
+        .map(|(start, _)| *start)
+        .collect();
+    hours.sort();
+    hours
+}
+
+/// Charges each charging station with a target (energy needed until a departure time) in the
+/// cheapest hours of the hourly price list published to MQTT. Inside a planned hour the charging
+/// station is set to its highest allowed current and the contactor is closed once the vehicle
+/// requests to charge; outside it is set to 100% PWM and the contactor is opened.
+pub async fn handle_planner(
+    registry: ConnectionRegistry,
+    mut bridge_request_rx: broadcast::Receiver<MqttMessage>,
+    evse_mqtt_tx: broadcast::Sender<MqttMessage>,
+    mut evse_mqtt_rx: broadcast::Receiver<MqttMessage>,
+    mut mqtt_input_rx: broadcast::Receiver<MqttInput>,
+    mut shutdown_rx: broadcast::Receiver<bool>,
+    settings: Config,
+) -> Result<()> {
+    let price_topic = settings.get_string("tariff.price_topic")?;
+    let json_pointer = settings.get_string("tariff.json_pointer").ok();
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        settings.get_int("tariff.interval_seconds").unwrap_or(60) as u64,
+    ));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    info!("PLAN: Reading hourly prices from {}", price_topic);
+
+    let mut prices: Vec<(DateTime<Utc>, f64)> = vec![];
+    let mut chargers: HashMap<String, Charger> = HashMap::new();
+    let mut plans: HashMap<String, Plan> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            Ok(_) = shutdown_rx.recv() => {
+                break;
+            }
+            receive = evse_mqtt_rx.recv() => {
+                match receive {
+                    Err(broadcast::error::RecvError::Closed) => break,
+                    Err(_) => {}
+                    Ok(msg) => match msg.message_type {
+                        MqttMessageType::new_connection => {
+                            if let Some(handshake) = msg.handshake {
+                                chargers.insert(msg.client_id, Charger {
+                                    limits: handshake.limits,
+                                    measurements: None,
+                                    measured: None,
+                                    charging: None,
+                                });
+                            }
+                        }
+                        MqttMessageType::connection_closed => {
+                            chargers.remove(&msg.client_id);
+                        }
+                        MqttMessageType::response_collect_data => {
+                            let charger = match chargers.get_mut(&msg.client_id) {
+                                Some(charger) => charger,
+                                None => continue,
+                            };
+                            let now = Instant::now();
+                            if let (Some(plan), Some(measured)) = (plans.get_mut(&msg.client_id), charger.measured) {
+                                let power = msg.measurements.as_ref().map_or(0.0, |m| m.power());
+                                plan.charged_kwh += energy_kwh(charger.power(), power, now.duration_since(measured)) as f32;
+                            }
+                            let was_plugged_in = matches!(
+                                charger.measurements.as_ref().map(|m| &m.pilot_voltage),
+                                Some(PilotVoltage::volt_9 | PilotVoltage::volt_6 | PilotVoltage::volt_3)
+                            );
+                            charger.measurements = msg.measurements;
+                            charger.measured = Some(now);
+                            // commands dropped by the connection (failsafe, limits, full queue) are
+                            // sent again on the next tick
+                            if let (Some(charging), Some(pwm_percent)) = (charger.charging, msg.pwm_percent) {
+                                if charging != (pwm_percent != 100) {
+                                    info!("PLAN: {} signals {}% while planned charging is {}, sending it again", msg.client_id, pwm_percent, if charging { "started" } else { "stopped" });
+                                    charger.charging = None;
+                                }
+                            }
+
+                            let measurements = match &charger.measurements {
+                                Some(measurements) => measurements,
+                                None => continue,
+                            };
+                            if was_plugged_in
+                                && measurements.pilot_voltage == PilotVoltage::volt_12
+                                && plans.remove(&msg.client_id).is_some()
+                            {
+                                info!("PLAN: Vehicle at {} unplugged, dropping its charge plan", msg.client_id);
+                                charger.charging = None;
+                            } else if charger.charging == Some(true)
+                                && msg.contactor_state == Some(false)
+                                && matches!(measurements.pilot_voltage, PilotVoltage::volt_6 | PilotVoltage::volt_3)
+                            {
+                                let command = MqttMessage {
+                                    contactor_state: Some(true),
+                                    ..MqttMessage::new(MqttMessageType::request_set_contactor_state, msg.client_id.clone())
+                                };
+                                if let Err(err) = registry.send(command, CommandOrigin::Bridge) {
+                                    error!("PLAN: Could not close the contactor of {}: {}", msg.client_id, err);
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Ok(msg) = bridge_request_rx.recv() => {
+                if let (MqttMessageType::request_set_charge_plan, Some(charge_plan)) = (&msg.message_type, &msg.charge_plan) {
+                    let reject = |reason: String| MqttMessage {
+                        rejection: Some(MqttMessageRejection {
+                            request_type: msg.message_type.clone(),
+                            reason,
+                        }),
+                        ..MqttMessage::new(MqttMessageType::command_rejected, msg.client_id.clone())
+                    };
+                    let response = match DateTime::parse_from_rfc3339(&charge_plan.departure) {
+                        _ if !chargers.contains_key(&msg.client_id) => {
+                            reject("charging station is not connected".to_string())
+                        }
+                        Err(err) => reject(format!("invalid departure {}: {}", charge_plan.departure, err)),
+                        Ok(_) if prices.is_empty() && charge_plan.energy_kwh > 0.0 => {
+                            reject(format!("no price list received from {}", price_topic))
+                        }
+                        Ok(_) if charge_plan.energy_kwh < 0.0 => {
+                            reject("energy_kwh must not be negative".to_string())
+                        }
+                        Ok(_) if charge_plan.energy_kwh == 0.0 => {
+                            info!("PLAN: Cancelling the charge plan of {}", msg.client_id);
+                            plans.remove(&msg.client_id);
+                            chargers.get_mut(&msg.client_id).unwrap().charging = None;
+                            MqttMessage {
+                                charge_plan: Some(charge_plan.clone()),
+                                ..MqttMessage::new(MqttMessageType::response_set_charge_plan, msg.client_id.clone())
+                            }
+                        }
+                        Ok(departure) => {
+                            let charger = &chargers[&msg.client_id];
+                            let mut plan = Plan {
+                                energy_kwh: charge_plan.energy_kwh,
+                                departure: departure.with_timezone(&Utc),
+                                charged_kwh: 0.0,
+                                hours: vec![],
+                            };
+                            let hours_needed = (plan.energy_kwh / charger.energy_per_hour()).ceil() as usize;
+                            plan.hours = cheapest_hours(&prices, Utc::now(), plan.departure, hours_needed);
+                            info!(
+                                "PLAN: Charging {}kWh at {} until {} in {} of {} hours needed",
+                                plan.energy_kwh, msg.client_id, plan.departure, plan.hours.len(), hours_needed
+                            );
+                            let response = MqttMessage {
+                                charge_plan: Some(plan.to_message()),
+                                ..MqttMessage::new(MqttMessageType::response_set_charge_plan, msg.client_id.clone())
+                            };
+                            plans.insert(msg.client_id, plan);
+                            response
+                        }
+                    };
+                    evse_mqtt_tx.send(MqttMessage { request_id: msg.request_id, ..response })?;
+                } else if msg.message_type == MqttMessageType::request_set_charge_plan {
+                    evse_mqtt_tx.send(MqttMessage::error_for(&msg, ErrorCode::invalid_request, "charge_plan cannot be null".to_string()))?;
+                }
+            }
+            Ok(input) = mqtt_input_rx.recv() => {
+                if input.topic == price_topic {
+                    match read_prices(&input.payload, json_pointer.as_deref()) {
+                        // keep planning with the previous list, its hours run out by themselves
+                        Some(list) if list.is_empty() => {
+                            error!("PLAN: Ignoring the empty price list from {}", input.topic)
+                        }
+                        Some(list) => {
+                            info!("PLAN: Received {} hourly prices", list.len());
+                            prices = list;
+                        }
+                        None => error!("PLAN: Could not read the price list from {}: {}", input.topic, input.payload),
+                    }
+                }
+            }
+            _ = interval.tick() => {
+                let now = Utc::now();
+                let mut finished = vec![];
+                for (client_id, plan) in plans.iter_mut() {
+                    let charger = match chargers.get_mut(client_id) {
+                        Some(charger) => charger,
+                        None => continue,
+                    };
+
+                    let remaining_kwh = plan.energy_kwh - plan.charged_kwh;
+                    let finishing = now >= plan.departure || remaining_kwh <= 0.0;
+                    let charge = if finishing {
+                        false
+                    } else {
+                        let hours_needed = (remaining_kwh / charger.energy_per_hour()).ceil() as usize;
+                        plan.hours = cheapest_hours(&prices, now, plan.departure, hours_needed);
+                        plan.hours.iter().any(|start| *start <= now && now < *start + chrono::Duration::hours(1))
+                    };
+
+                    // retried on the next tick unless all commands are sent
+                    let changed = charger.charging != Some(charge);
+                    if changed && charge {
+                        info!("PLAN: Starting planned charging of {}", client_id);
+                        let command = MqttMessage {
+                            charge_current: Some(charger.max_current()),
+                            ..MqttMessage::new(MqttMessageType::request_set_charge_current, client_id.clone())
+                        };
+                        match registry.send(command, CommandOrigin::Bridge) {
+                            Ok(()) => charger.charging = Some(true),
+                            Err(err) => error!("PLAN: Could not start charging {}: {}", client_id, err),
+                        }
+                    } else if changed {
+                        info!("PLAN: Stopping planned charging of {}", client_id);
+                        let commands = [
+                            MqttMessage {
+                                pwm_percent: Some(100),
+                                ..MqttMessage::new(MqttMessageType::request_set_pwm_percent, client_id.clone())
+                            },
+                            MqttMessage {
+                                contactor_state: Some(false),
+                                ..MqttMessage::new(MqttMessageType::request_set_contactor_state, client_id.clone())
+                            },
+                        ];
+                        let mut sent = true;
+                        for command in commands {
+                            if let Err(err) = registry.send(command, CommandOrigin::Bridge) {
+                                error!("PLAN: Could not stop charging {}: {}", client_id, err);
+                                sent = false;
+                            }
+                        }
+                        if sent {
+                            charger.charging = Some(false);
+                        }
+                    }
+
+                    // the plan is kept until charging is stopped
+                    if finishing && charger.charging == Some(false) {
+                        if now >= plan.departure {
+                            info!("PLAN: Departure time of {} reached with {}kWh charged", client_id, plan.charged_kwh);
+                        } else {
+                            info!("PLAN: {} charged {}kWh as planned", client_id, plan.charged_kwh);
+                        }
+                        finished.push(client_id.clone());
+                    }
+                }
+                for client_id in finished {
+                    plans.remove(&client_id);
+                    if let Some(charger) = chargers.get_mut(&client_id) {
+                        charger.charging = None;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn prices(list: &[(&str, f64)]) -> Vec<(DateTime<Utc>, f64)> {
+        list.iter()
+            .map(|(start, price)| (at(start), *price))
+            .collect()
+    }
+
+    #[test]
+    fn reads_prices() {
+        let payload = r#"[
+            {"start": "2022-10-18T01:00:00+02:00", "price": 0.21},
+            {"start": "2022-10-18T02:00:00+02:00", "price": 0.18}
+        ]"#;
+        assert_eq!(
+            read_prices(payload, None),
+            Some(prices(&[
+                ("2022-10-17T23:00:00Z", 0.21),
+                ("2022-10-18T00:00:00Z", 0.18)
+            ]))
+        );
+    }
+
+    #[test]
+    fn reads_prices_at_a_json_pointer() {
+        let payload = r#"{"today": [{"start": "2022-10-18T01:00:00+02:00", "price": 1}]}"#;
+        assert_eq!(
+            read_prices(payload, Some("/today")),
+            Some(prices(&[("2022-10-17T23:00:00Z", 1.0)]))
+        );
+        assert_eq!(read_prices(payload, Some("/tomorrow")), None);
+    }
+
+    #[test]
+    fn rejects_invalid_price_lists() {
+        assert_eq!(read_prices("no json", None), None);
+        assert_eq!(read_prices(r#"{"price": 0.21}"#, None), None);
+        // a single invalid entry invalidates the list
+        let payload = r#"[
+            {"start": "2022-10-18T01:00:00+02:00", "price": 0.21},
+            {"start": "tomorrow", "price": 0.18}
+        ]"#;
+        assert_eq!(read_prices(payload, None), None);
+        assert_eq!(read_prices("[]", None), Some(vec![]));
+    }
+
+    #[test]
+    fn picks_the_cheapest_hours_in_order_of_time() {
+        let prices = prices(&[
+            ("2022-10-18T00:00:00Z", 0.30),
+            ("2022-10-18T01:00:00Z", 0.10),
+            ("2022-10-18T02:00:00Z", 0.20),
+            ("2022-10-18T03:00:00Z", 0.10),
+            ("2022-10-18T04:00:00Z", 0.05),
+        ]);
+        let hours = cheapest_hours(
+            &prices,
+            at("2022-10-18T00:30:00Z"),
+            at("2022-10-18T04:00:00Z"),
+            2,
+        );
+        assert_eq!(
+            hours,
+            vec![at("2022-10-18T01:00:00Z"), at("2022-10-18T03:00:00Z")]
+        );
+    }
+
+    #[test]
+    fn counts_the_current_hour() {
+        let prices = prices(&[
+            ("2022-10-18T00:00:00Z", 0.10),
+            ("2022-10-18T01:00:00Z", 0.20),
+        ]);
+        let hours = cheapest_hours(
+            &prices,
+            at("2022-10-18T00:59:00Z"),
+            at("2022-10-18T02:00:00Z"),
+            1,
+        );
+        assert_eq!(hours, vec![at("2022-10-18T00:00:00Z")]);
+        let hours = cheapest_hours(
+            &prices,
+            at("2022-10-18T01:00:00Z"),
+            at("2022-10-18T02:00:00Z"),
+            1,
+        );
+        assert_eq!(hours, vec![at("2022-10-18T01:00:00Z")]);
+    }
+
+    #[test]
+    fn plans_nothing_once_the_departure_has_passed() {
+        let prices = prices(&[
+            ("2022-10-18T00:00:00Z", 0.10),
+            ("2022-10-18T01:00:00Z", 0.20),
+        ]);
+        let hours = cheapest_hours(
+            &prices,
+            at("2022-10-18T03:00:00Z"),
+            at("2022-10-18T02:00:00Z"),
+            2,
+        );
+        assert!(hours.is_empty());
+        let hours = cheapest_hours(
+            &prices,
+            at("2022-10-18T00:30:00Z"),
+            at("2022-10-18T00:15:00Z"),
+            2,
+        );
+        assert_eq!(hours, vec![at("2022-10-18T00:00:00Z")]);
+    }
+
+    #[test]
+    fn keeps_both_hours_of_the_end_of_daylight_saving_time() {
+        // 02:00 local time occurs twice on 2022-10-30 in central Europe
+        let payload = r#"[
+            {"start": "2022-10-30T01:00:00+02:00", "price": 0.30},
+            {"start": "2022-10-30T02:00:00+02:00", "price": 0.10},
+            {"start": "2022-10-30T02:00:00+01:00", "price": 0.20},
+            {"start": "2022-10-30T03:00:00+01:00", "price": 0.40}
+        ]"#;
+        let prices = read_prices(payload, None).unwrap();
+        let hours = cheapest_hours(
+            &prices,
+            at("2022-10-30T01:00:00+02:00"),
+            at("2022-10-30T04:00:00+01:00"),
+            2,
+        );
+        assert_eq!(
+            hours,
+            vec![at("2022-10-30T00:00:00Z"), at("2022-10-30T01:00:00Z")]
+        );
+    }
+}
@@ -1,123 +1,186 @@
 use std::{
-    error,
+    error, fmt,
     io::{Error, ErrorKind},
 };
 
 use byteorder::{BigEndian, ByteOrder};
+use bytes::{Buf, Bytes, BytesMut};
 use serde::{Deserialize, Serialize, __private::from_utf8_lossy};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::limits::EvseLimits;
 
 type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
 
-#[allow(unused_variables)]
 pub fn evse_to_mqtt(
     client_id: String,
     message_type: u8,
-    payload_length: u32,
     payload: &[u8],
-) -> Result<MqttMessage> {
+) -> std::result::Result<MqttMessage, DecodeError> {
     match message_type {
-        RESPONSE_TYPE_PONG => Ok(MqttMessage {
-            message_type: MqttMessageType::response_ping,
+        RESPONSE_TYPE_PONG => Ok(MqttMessage::new(MqttMessageType::response_ping, client_id)),
+        NOTIFY => Ok(MqttMessage::new(MqttMessageType::notify, client_id)),
+        RESPONSE_TYPE_SET_PWM_PERCENT => Ok(MqttMessage::new(
+            MqttMessageType::response_set_pwm_percent,
             client_id,
-            handshake: None,
-            firmware: None,
-            pwm_percent: None,
-            contactor_state: None,
-            measurements: None,
-        }),
-        NOTIFY => Ok(MqttMessage {
-            message_type: MqttMessageType::notify,
+        )),
+        RESPONSE_TYPE_SET_CONTACTOR_STATE => Ok(MqttMessage::new(
+            MqttMessageType::response_set_contactor_state,
             client_id,
-            handshake: None,
-            firmware: None,
-            pwm_percent: None,
-            contactor_state: None,
-            measurements: None,
-        }),
-        RESPONSE_TYPE_SET_PWM_PERCENT => Ok(MqttMessage {
-            message_type: MqttMessageType::response_set_pwm_percent,
-            client_id,
-            handshake: None,
-            firmware: None,
-            pwm_percent: None,
-            contactor_state: None,
-            measurements: None,
-        }),
-        RESPONSE_TYPE_SET_CONTACTOR_STATE => Ok(MqttMessage {
-            message_type: MqttMessageType::response_set_contactor_state,
-            client_id,
-            handshake: None,
-            firmware: None,
-            pwm_percent: None,
-            contactor_state: None,
-            measurements: None,
-        }),
+        )),
         RESPONSE_TYPE_COLLECT_DATA => {
-            let contactor_state = if payload[0] == 1 { true } else { false };
-            let pwm_percent = payload[1];
-            let pilot_volt = payload[2];
-            let proximity_pilot_amps = payload[3];
+            let mut reader = PayloadReader::new(message_type, payload);
+            let contactor_state = reader.read_u8("contactor_state")? == 1;
+            let pwm_percent = reader.read_u8("pwm_percent")?;
+
+            let pilot_voltage = match reader.read_u8("pilot_voltage")? {
+                0 => PilotVoltage::volt_12,
+                1 => PilotVoltage::volt_9,
+                2 => PilotVoltage::volt_6,
+                3 => PilotVoltage::volt_3,
+                4 => PilotVoltage::fault,
+                value => return Err(reader.unsupported_value("pilot_voltage", value)),
+            };
+            let proximity_pilot_amps = match reader.read_u8("proximity_pilot_amps")? {
+                0 => ProximityPilotAmps::amp_13,
+                1 => ProximityPilotAmps::amp_20,
+                2 => ProximityPilotAmps::amp_32,
+                3 => ProximityPilotAmps::no_cable,
+                value => return Err(reader.unsupported_value("proximity_pilot_amps", value)),
+            };
+            let vehicle_state = pilot_voltage.vehicle_state();
 
             let measurements = MqttMessageMeasurements {
-                pilot_voltage: match pilot_volt {
-                    0 => PilotVoltage::volt_12,
-                    1 => PilotVoltage::volt_9,
-                    2 => PilotVoltage::volt_6,
-                    3 => PilotVoltage::volt_3,
-                    4 => PilotVoltage::fault,
-                    _ => {
-                        return Err(Error::new(
-                            ErrorKind::InvalidData,
-                            format!("Unsupported pilot_volt={}", pilot_volt),
-                        )
-                        .into())
-                    }
-                },
-                proximity_pilot_amps: match proximity_pilot_amps {
-                    0 => ProximityPilotAmps::amp_13,
-                    1 => ProximityPilotAmps::amp_20,
-                    2 => ProximityPilotAmps::amp_32,
-                    3 => ProximityPilotAmps::no_cable,
-                    _ => {
-                        return Err(Error::new(
-                            ErrorKind::InvalidData,
-                            format!("Unsupported proximity_pilot_amps={}", proximity_pilot_amps),
-                        )
-                        .into())
-                    }
-                },
-                phase1_millivolts: BigEndian::read_u32(&payload[4..]),
-                phase2_millivolts: BigEndian::read_u32(&payload[8..]),
-                phase3_millivolts: BigEndian::read_u32(&payload[12..]),
-                phase1_milliamps: BigEndian::read_u32(&payload[16..]),
-                phase2_milliamps: BigEndian::read_u32(&payload[20..]),
-                phase3_milliamps: BigEndian::read_u32(&payload[24..]),
-                wifi_rssi: BigEndian::read_i32(&payload[28..]),
-                uptime_milliseconds: BigEndian::read_i32(&payload[32..]),
-                current_control_pilot_adc: BigEndian::read_u32(&payload[36..]),
-                current_proximity_pilot_adc: BigEndian::read_u32(&payload[40..]),
-                logging_buffer: from_utf8_lossy(&payload[44..]).into_owned(),
+                pilot_voltage,
+                vehicle_state_meaning: vehicle_state.meaning().to_string(),
+                vehicle_state,
+                cable_max_amps: proximity_pilot_amps.max_amps(),
+                proximity_pilot_amps,
+                phase1_millivolts: reader.read_u32("phase1_millivolts")?,
+                phase2_millivolts: reader.read_u32("phase2_millivolts")?,
+                phase3_millivolts: reader.read_u32("phase3_millivolts")?,
+                phase1_milliamps: reader.read_u32("phase1_milliamps")?,
+                phase2_milliamps: reader.read_u32("phase2_milliamps")?,
+                phase3_milliamps: reader.read_u32("phase3_milliamps")?,
+                wifi_rssi: reader.read_i32("wifi_rssi")?,
+                uptime_milliseconds: reader.read_i32("uptime_milliseconds")?,
+                current_control_pilot_adc: reader.read_u32("current_control_pilot_adc")?,
+                current_proximity_pilot_adc: reader.read_u32("current_proximity_pilot_adc")?,
+                logging_buffer: from_utf8_lossy(reader.rest()).into_owned(),
             };
 
             Ok(MqttMessage {
-                message_type: MqttMessageType::request_data_collection,
-                client_id,
-                handshake: None,
-                firmware: None,
                 pwm_percent: Some(pwm_percent),
+                charge_current: Some(pwm_percent_to_amps(pwm_percent)),
                 contactor_state: Some(contactor_state),
                 measurements: Some(measurements),
+                ..MqttMessage::new(MqttMessageType::response_collect_data, client_id)
             })
         }
 
-        _ => Err(Error::new(
-            ErrorKind::InvalidData,
-            format!("Unpported message type {}", message_type),
-        ).into()),
+        _ => Err(DecodeError {
+            message_type,
+            field: "message_type".to_string(),
+            expected_length: None,
+            actual_length: payload.len(),
+            reason: format!("unsupported message type {}", message_type),
+        }),
+    }
+}
+
+/// A frame received from the EVSE which could not be decoded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DecodeError {
+    /// Message type of the frame
+    pub message_type: u8,
+    /// The field which could not be decoded
+    pub field: String,
+    /// Payload length needed to decode the field, if the payload was too short
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_length: Option<usize>,
+    pub actual_length: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "could not decode {} of message type {}: {}",
+            self.field, self.message_type, self.reason
+        )
+    }
+}
+
+impl error::Error for DecodeError {}
+
+/// Reads the fields of a payload in order, checking the payload is long enough for each field.
+struct PayloadReader<'a> {
+    message_type: u8,
+    payload: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> PayloadReader<'a> {
+    fn new(message_type: u8, payload: &'a [u8]) -> PayloadReader<'a> {
+        PayloadReader {
+            message_type,
+            payload,
+            offset: 0,
+        }
+    }
+
+    fn take(&mut self, field: &str, length: usize) -> std::result::Result<&'a [u8], DecodeError> {
+        let end = self.offset + length;
+        if self.payload.len() < end {
+            return Err(DecodeError {
+                message_type: self.message_type,
+                field: field.to_string(),
+                expected_length: Some(end),
+                actual_length: self.payload.len(),
+                reason: format!(
+                    "payload of {} bytes is too short, {} expected",
+                    self.payload.len(),
+                    end
+                ),
+            });
+        }
+        let bytes = &self.payload[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self, field: &str) -> std::result::Result<u8, DecodeError> {
+        Ok(self.take(field, 1)?[0])
+    }
+
+    fn read_u32(&mut self, field: &str) -> std::result::Result<u32, DecodeError> {
+        Ok(BigEndian::read_u32(self.take(field, 4)?))
+    }
+
+    fn read_i32(&mut self, field: &str) -> std::result::Result<i32, DecodeError> {
+        Ok(BigEndian::read_i32(self.take(field, 4)?))
+    }
+
+    /// The remaining payload
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.payload[self.offset..];
+        self.offset = self.payload.len();
+        rest
+    }
+
+    fn unsupported_value(&self, field: &str, value: u8) -> DecodeError {
+        DecodeError {
+            message_type: self.message_type,
+            field: field.to_string(),
+            expected_length: None,
+            actual_length: self.payload.len(),
+            reason: format!("unsupported value {}", value),
+        }
     }
 }
 
-pub fn mqtt_to_evse(msg: MqttMessage) -> Result<Vec<u8>> {
+fn mqtt_to_evse(msg: MqttMessage) -> Result<Vec<u8>> {
     let mut vec = Vec::new();
     match msg.message_type {
         MqttMessageType::request_ping => {
@@ -145,13 +208,30 @@ pub fn mqtt_to_evse(msg: MqttMessage) -> Result<Vec<u8>> {
             byteorder::WriteBytesExt::write_u32::<BigEndian>(&mut vec, 0).unwrap();
         }
         MqttMessageType::request_set_pwm_percent => {
-            let percent = msg.pwm_percent.unwrap();
+            let percent = msg.pwm_percent.ok_or(Error::new(
+                ErrorKind::InvalidData,
+                "pwm_percent cannot be null",
+            ))?;
             vec.push(REQUEST_TYPE_SET_PWM_PERCENT);
             byteorder::WriteBytesExt::write_u32::<BigEndian>(&mut vec, 1).unwrap();
             byteorder::WriteBytesExt::write_u8(&mut vec, percent).unwrap();
         }
+        MqttMessageType::request_set_charge_current => {
+            let charge_current = msg.charge_current.ok_or(Error::new(
+                ErrorKind::InvalidData,
+                "charge_current cannot be null",
+            ))?;
+            vec.push(REQUEST_TYPE_SET_PWM_PERCENT);
+            byteorder::WriteBytesExt::write_u32::<BigEndian>(&mut vec, 1).unwrap();
+            byteorder::WriteBytesExt::write_u8(&mut vec, amps_to_pwm_percent(charge_current)?)
+                .unwrap();
+        }
         MqttMessageType::request_set_contactor_state => {
-            let contactor_state = if msg.contactor_state.unwrap() { 1 } else { 0 };
+            let contactor_state = msg.contactor_state.ok_or(Error::new(
+                ErrorKind::InvalidData,
+                "contactor_state cannot be null",
+            ))?;
+            let contactor_state = if contactor_state { 1 } else { 0 };
             vec.push(REQUEST_TYPE_SET_CONTACTOR_STATE);
             byteorder::WriteBytesExt::write_u32::<BigEndian>(&mut vec, 1).unwrap();
             byteorder::WriteBytesExt::write_u8(&mut vec, contactor_state).unwrap();
@@ -169,6 +249,134 @@ pub fn mqtt_to_evse(msg: MqttMessage) -> Result<Vec<u8>> {
     Ok(vec)
 }
 
+/// Converts the allowed charge current into the control pilot duty cycle according to
+/// IEC 61851-1: 0A switches charging off (100%), 6A to 51A map to `amps / 0.6` and
+/// 51A to 80A to `amps / 2.5 + 64`. The duty cycle is rounded down, so the EVSE never
+/// signals more than requested.
+pub fn amps_to_pwm_percent(amps: f32) -> Result<u8> {
+    if amps == 0.0 {
+        return Ok(100);
+    }
+    if !(6.0..=80.0).contains(&amps) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unsupported charge_current={}, must be 0 or between 6 and 80", amps),
+        )
+        .into());
+    }
+
+    let deciamps = (amps * 10.0).round() as u32;
+    let percent = if deciamps <= 510 {
+        deciamps / 6
+    } else {
+        (deciamps / 25 + 64).max(85)
+    };
+    Ok(percent as u8)
+}
+
+/// The charge current signalled by the given duty cycle, see [`amps_to_pwm_percent`].
+/// Duty cycles not allowing to charge are reported as 0A.
+pub fn pwm_percent_to_amps(pwm_percent: u8) -> f32 {
+    match pwm_percent {
+        10..=85 => pwm_percent as f32 * 0.6,
+        86..=96 => (pwm_percent as f32 - 64.0) * 2.5,
+        _ => 0.0,
+    }
+}
+
+/// Length of the handshake: 16 bytes serial, 1 byte firmware version
+const HANDSHAKE_LENGTH: usize = 17;
+/// Length of the frame header: 1 byte message type, 4 bytes big-endian payload length
+const HEADER_LENGTH: usize = 5;
+
+/// What the EVSE sends: the handshake once after connecting, then framed messages.
+#[derive(Debug, PartialEq)]
+pub enum EvseFrame {
+    Handshake {
+        serial: [u8; 16],
+        firmware_version: u8,
+    },
+    Message {
+        message_type: u8,
+        payload: Bytes,
+    },
+}
+
+/// Framing of the EVSE connection. Decodes the handshake followed by frames of a 1 byte message
+/// type and a 4 byte big-endian payload length, and encodes requests to the EVSE the same way.
+pub struct EvseCodec {
+    handshake_received: bool,
+    max_frame_size: usize,
+}
+
+impl EvseCodec {
+    /// `max_frame_size` limits the payload length of received frames.
+    pub fn new(max_frame_size: usize) -> EvseCodec {
+        EvseCodec {
+            handshake_received: false,
+            max_frame_size,
+        }
+    }
+}
+
+impl Decoder for EvseCodec {
+    type Item = EvseFrame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> std::result::Result<Option<EvseFrame>, Error> {
+        if !self.handshake_received {
+            if src.len() < HANDSHAKE_LENGTH {
+                src.reserve(HANDSHAKE_LENGTH - src.len());
+                return Ok(None);
+            }
+            let mut serial = [0u8; 16];
+            serial.copy_from_slice(&src[..16]);
+            let firmware_version = src[16];
+            src.advance(HANDSHAKE_LENGTH);
+            self.handshake_received = true;
+            return Ok(Some(EvseFrame::Handshake {
+                serial,
+                firmware_version,
+            }));
+        }
+
+        if src.len() < HEADER_LENGTH {
+            src.reserve(HEADER_LENGTH - src.len());
+            return Ok(None);
+        }
+        let payload_length = BigEndian::read_u32(&src[1..HEADER_LENGTH]) as usize;
+        if payload_length > self.max_frame_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "payload length {} of message type {} exceeds max_frame_size={}",
+                    payload_length, src[0], self.max_frame_size
+                ),
+            ));
+        }
+        if src.len() < HEADER_LENGTH + payload_length {
+            src.reserve(HEADER_LENGTH + payload_length - src.len());
+            return Ok(None);
+        }
+
+        let message_type = src[0];
+        src.advance(HEADER_LENGTH);
+        Ok(Some(EvseFrame::Message {
+            message_type,
+            payload: src.split_to(payload_length).freeze(),
+        }))
+    }
+}
+
+impl Encoder<MqttMessage> for EvseCodec {
+    type Error = Box<dyn error::Error>;
+
+    fn encode(&mut self, msg: MqttMessage, dst: &mut BytesMut) -> Result<()> {
+        dst.extend_from_slice(&mqtt_to_evse(msg)?);
+        Ok(())
+    }
+}
+
 const RESPONSE_TYPE_PONG: u8 = 1;
 const RESPONSE_TYPE_COLLECT_DATA: u8 = 2;
 const RESPONSE_TYPE_SET_PWM_PERCENT: u8 = 3;
@@ -185,21 +393,182 @@ const REQUEST_TYPE_SET_CONTACTOR_STATE: u8 = 5;
 pub struct MqttMessage {
     pub message_type: MqttMessageType,
     pub client_id: String,
+    /// Chosen by the sender of a request and echoed on its response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub handshake: Option<MqttMessageHandshake>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection: Option<MqttMessageConnection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub firmware: Option<MqttMessageFirmware>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub pwm_percent: Option<u8>,
+    /// Charge current in ampere, the alternative to `pwm_percent`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charge_current: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charge_mode: Option<ChargeMode>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub contactor_state: Option<bool>,
+    /// Charge regardless of the schedule until the vehicle is unplugged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charge_now: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charge_plan: Option<MqttMessageChargePlan>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub measurements: Option<MqttMessageMeasurements>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub power: Option<MqttMessagePower>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vehicle_state: Option<MqttMessageVehicleState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<MqttMessageSession>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_history: Option<MqttMessageSessionHistory>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejection: Option<MqttMessageRejection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol_error: Option<DecodeError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<MqttMessageError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl MqttMessage {
+    pub fn new(message_type: MqttMessageType, client_id: String) -> MqttMessage {
+        MqttMessage {
+            message_type,
+            client_id,
+            request_id: None,
+            handshake: None,
+            connection: None,
+            firmware: None,
+            pwm_percent: None,
+            charge_current: None,
+            charge_mode: None,
+            contactor_state: None,
+            charge_now: None,
+            charge_plan: None,
+            measurements: None,
+            power: None,
+            vehicle_state: None,
+            session: None,
+            session_history: None,
+            rejection: None,
+            protocol_error: None,
+            error: None,
+            reason: None,
+        }
+    }
+
+    /// An `error` answering a request which could not be handled.
+    pub fn error_for(request: &MqttMessage, code: ErrorCode, reason: String) -> MqttMessage {
+        MqttMessage {
+            request_id: request.request_id.clone(),
+            error: Some(MqttMessageError {
+                code,
+                message_type: Some(format!("{:?}", request.message_type)),
+                reason,
+            }),
+            ..MqttMessage::new(MqttMessageType::error, request.client_id.clone())
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MqttMessageHandshake {
+    pub serial: String,
     pub firmware_version: u8,
+    pub limits: EvseLimits,
+}
+
+/// The connection of a charging station replacing, or refused because of, one which had not
+/// been closed yet.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MqttMessageConnection {
+    pub peer_addr: String,
+    pub previous_peer_addr: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MqttMessageRejection {
+    pub request_type: MqttMessageType,
+    pub reason: String,
+}
+
+/// A message received from MQTT which the bridge could not handle.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MqttMessageError {
+    pub code: ErrorCode,
+    /// `message_type` of the offending message, as far as it could be read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_type: Option<String>,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum ErrorCode {
+    /// The payload is not JSON or does not match the message format
+    invalid_json,
+    unknown_message_type,
+    /// The request cannot be sent to the EVSE, e.g. a required field is missing
+    invalid_request,
+    /// No charging station with this `client_id` is connected
+    not_connected,
+    /// The feature answering this request is not enabled
+    not_handled,
+}
+
+/// A transition of the IEC 61851 vehicle state between two data collections.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MqttMessageVehicleState {
+    pub previous: VehicleState,
+    pub current: VehicleState,
+    pub meaning: String,
+}
+
+/// A charging session, from plugging in the vehicle until unplugging it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MqttMessageSession {
+    /// Start in RFC 3339
+    pub started: String,
+    /// End in RFC 3339, once the session has ended
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ended: Option<String>,
+    pub duration_seconds: u64,
+    pub energy_kwh: f32,
+    /// Highest current of any phase in ampere
+    pub peak_current: f32,
+}
+
+/// The completed charging sessions started within `from` (inclusive) and `to` (exclusive).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MqttMessageSessionHistory {
+    /// RFC 3339
+    pub from: String,
+    /// RFC 3339
+    pub to: String,
+    /// Set by the bridge
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sessions: Vec<MqttMessageSession>,
+    /// Set by the bridge
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub energy_kwh: Option<f32>,
+}
+
+/// Energy to charge until the departure time, in the cheapest hours of the price list.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MqttMessageChargePlan {
+    /// Energy needed in kWh, 0 cancels the plan
+    pub energy_kwh: f32,
+    /// Departure time in RFC 3339, e.g. `2022-10-18T07:00:00+02:00`
+    pub departure: String,
+    /// Start of the hours planned for charging, set by the bridge
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hours: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -210,40 +579,168 @@ pub struct MqttMessageFirmware {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MqttMessageMeasurements {
-    pilot_voltage: PilotVoltage,
-    proximity_pilot_amps: ProximityPilotAmps,
-    phase1_millivolts: u32,
-    phase2_millivolts: u32,
-    phase3_millivolts: u32,
-    phase1_milliamps: u32,
-    phase2_milliamps: u32,
-    phase3_milliamps: u32,
-    wifi_rssi: i32,
-    uptime_milliseconds: i32,
-    current_control_pilot_adc: u32,
-    current_proximity_pilot_adc: u32,
-    logging_buffer: String,
+    pub pilot_voltage: PilotVoltage,
+    /// IEC 61851 state of the vehicle, decoded from `pilot_voltage`
+    pub vehicle_state: VehicleState,
+    pub vehicle_state_meaning: String,
+    pub proximity_pilot_amps: ProximityPilotAmps,
+    /// Current rating of the plugged in cable, decoded from `proximity_pilot_amps`
+    pub cable_max_amps: Option<u8>,
+    pub phase1_millivolts: u32,
+    pub phase2_millivolts: u32,
+    pub phase3_millivolts: u32,
+    pub phase1_milliamps: u32,
+    pub phase2_milliamps: u32,
+    pub phase3_milliamps: u32,
+    pub wifi_rssi: i32,
+    pub uptime_milliseconds: i32,
+    pub current_control_pilot_adc: u32,
+    pub current_proximity_pilot_adc: u32,
+    pub logging_buffer: String,
+}
+
+impl MqttMessageMeasurements {
+    /// Active power per phase in watts, voltage times current
+    pub fn phase_powers(&self) -> [f32; 3] {
+        [
+            (self.phase1_millivolts, self.phase1_milliamps),
+            (self.phase2_millivolts, self.phase2_milliamps),
+            (self.phase3_millivolts, self.phase3_milliamps),
+        ]
+        .map(|(millivolts, milliamps)| millivolts as f32 * milliamps as f32 / 1_000_000.0)
+    }
+
+    /// Power drawn by the vehicle in watts, the sum of voltage times current of all phases
+    pub fn power(&self) -> f32 {
+        self.phase_powers().iter().sum()
+    }
+
+    /// Number of phases carrying at least 1A
+    pub fn active_phases(&self) -> u8 {
+        [self.phase1_milliamps, self.phase2_milliamps, self.phase3_milliamps]
+            .iter()
+            .filter(|milliamps| **milliamps >= 1000)
+            .count() as u8
+    }
+
+    /// Highest current of all phases in ampere
+    pub fn max_current(&self) -> f32 {
+        self.phase1_milliamps
+            .max(self.phase2_milliamps)
+            .max(self.phase3_milliamps) as f32
+            / 1000.0
+    }
 }
 
+/// Power and energy derived from the measurements of a data collection.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MqttMessagePower {
+    pub phase1_watts: f32,
+    pub phase2_watts: f32,
+    pub phase3_watts: f32,
+    pub total_watts: f32,
+    /// Number of phases carrying at least 1A
+    pub active_phases: u8,
+    /// Energy in kWh since the charging station connected to the bridge
+    pub energy_kwh: f64,
+    /// Set on the first data collection of a connection, where `energy_kwh` starts from 0 again
+    pub energy_reset: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum MqttMessageType {
     new_connection,
+    connection_closed,
+    connection_replaced,
+    connection_rejected,
     notify,
+    command_rejected,
+    failsafe_engaged,
+    failsafe_cleared,
+    command_deferred,
+    command_dropped,
+    response_timeout,
+    protocol_error,
+    error,
+    vehicle_state_changed,
+    session_started,
+    session_progress,
+    session_ended,
 
     response_ping,
     response_collect_data,
     response_set_pwm_percent,
     response_set_contactor_state,
+    response_set_charge_mode,
+    response_charge_now,
+    response_set_charge_plan,
+    response_session_history,
 
     request_ping,
     request_data_collection,
     request_firmware,
     request_set_pwm_percent,
+    request_set_charge_current,
     request_set_contactor_state,
+    request_set_charge_mode,
+    request_charge_now,
+    request_set_charge_plan,
+    request_session_history,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl MqttMessageType {
+    /// The response the EVSE answers a request with
+    pub fn response_type(&self) -> Option<MqttMessageType> {
+        match self {
+            MqttMessageType::request_ping => Some(MqttMessageType::response_ping),
+            MqttMessageType::request_data_collection => {
+                Some(MqttMessageType::response_collect_data)
+            }
+            MqttMessageType::request_set_pwm_percent
+            | MqttMessageType::request_set_charge_current => {
+                Some(MqttMessageType::response_set_pwm_percent)
+            }
+            MqttMessageType::request_set_contactor_state => {
+                Some(MqttMessageType::response_set_contactor_state)
+            }
+            _ => None,
+        }
+    }
+
+    /// Requests setting the charge current, either in amps or as duty cycle
+    pub fn sets_current(&self) -> bool {
+        matches!(
+            self,
+            MqttMessageType::request_set_pwm_percent | MqttMessageType::request_set_charge_current
+        )
+    }
+
+    /// Requests answered by the bridge itself instead of being sent to the EVSE
+    pub fn is_bridge_request(&self) -> bool {
+        matches!(
+            self,
+            MqttMessageType::request_set_charge_mode
+                | MqttMessageType::request_charge_now
+                | MqttMessageType::request_set_charge_plan
+                | MqttMessageType::request_session_history
+        )
+    }
+}
+
+/// How the load balancer picks the charge current of a charging station.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum ChargeMode {
+    /// As much as the load balancer allows
+    fast,
+    /// From PV surplus only, pausing while there is not enough surplus
+    solar_only,
+    /// From PV surplus, but at least with the minimum current
+    solar_plus_min,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum PilotVoltage {
     volt_12,
@@ -253,11 +750,326 @@ pub enum PilotVoltage {
     fault,
 }
 
+impl PilotVoltage {
+    pub fn vehicle_state(&self) -> VehicleState {
+        match self {
+            PilotVoltage::volt_12 => VehicleState::A,
+            PilotVoltage::volt_9 => VehicleState::B,
+            PilotVoltage::volt_6 => VehicleState::C,
+            PilotVoltage::volt_3 => VehicleState::D,
+            PilotVoltage::fault => VehicleState::E,
+        }
+    }
+}
+
+/// Vehicle states according to IEC 61851-1.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum VehicleState {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+}
+
+impl VehicleState {
+    pub fn meaning(&self) -> &'static str {
+        match self {
+            VehicleState::A => "no vehicle connected",
+            VehicleState::B => "vehicle connected, not ready to charge",
+            VehicleState::C => "vehicle charging",
+            VehicleState::D => "vehicle charging, ventilation required",
+            VehicleState::E => "error (control pilot shorted or out of range)",
+            VehicleState::F => "charging station not available",
+        }
+    }
+}
+
 #[allow(non_camel_case_types)]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ProximityPilotAmps {
     amp_13,
     amp_20,
     amp_32,
     no_cable,
 }
+
+impl ProximityPilotAmps {
+    /// Current rating of the plugged in cable
+    pub fn max_amps(&self) -> Option<u8> {
+        match self {
+            ProximityPilotAmps::amp_13 => Some(13),
+            ProximityPilotAmps::amp_20 => Some(20),
+            ProximityPilotAmps::amp_32 => Some(32),
+            ProximityPilotAmps::no_cable => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HANDSHAKE: [u8; 17] = [
+        0x10, 0xBA, 0x23, 0xAB, 0x50, 0x53, 0x4D, 0x53, 0x30, 0x2E, 0x31, 0x20, 0xFF, 0x16, 0x23,
+        0x32, 7,
+    ];
+
+    fn frame(message_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![message_type];
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Feeds the stream in chunks of the given size, decoding after every chunk.
+    fn decode_in_chunks(stream: &[u8], chunk_size: usize) -> Vec<EvseFrame> {
+        let mut codec = EvseCodec::new(1024);
+        let mut buf = BytesMut::new();
+        let mut frames = vec![];
+        for chunk in stream.chunks(chunk_size) {
+            buf.extend_from_slice(chunk);
+            while let Some(frame) = codec.decode(&mut buf).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert!(buf.is_empty());
+        frames
+    }
+
+    fn handshake() -> EvseFrame {
+        let mut serial = [0u8; 16];
+        serial.copy_from_slice(&HANDSHAKE[..16]);
+        EvseFrame::Handshake {
+            serial,
+            firmware_version: 7,
+        }
+    }
+
+    #[test]
+    fn decodes_fragmented_stream() {
+        let mut stream = HANDSHAKE.to_vec();
+        stream.extend(frame(RESPONSE_TYPE_PONG, &[]));
+        stream.extend(frame(RESPONSE_TYPE_COLLECT_DATA, &[0xAB; 60]));
+        stream.extend(frame(RESPONSE_TYPE_SET_PWM_PERCENT, &[]));
+
+        let expected = vec![
+            handshake(),
+            EvseFrame::Message {
+                message_type: RESPONSE_TYPE_PONG,
+                payload: Bytes::new(),
+            },
+            EvseFrame::Message {
+                message_type: RESPONSE_TYPE_COLLECT_DATA,
+                payload: Bytes::from(vec![0xAB; 60]),
+            },
+            EvseFrame::Message {
+                message_type: RESPONSE_TYPE_SET_PWM_PERCENT,
+                payload: Bytes::new(),
+            },
+        ];
+        for chunk_size in [1, 2, 3, 4, 5, 7, 16, 17, 18, 64, stream.len()] {
+            assert_eq!(
+                decode_in_chunks(&stream, chunk_size),
+                expected,
+                "chunk_size={}",
+                chunk_size
+            );
+        }
+    }
+
+    #[test]
+    fn waits_for_the_complete_payload() {
+        let mut codec = EvseCodec::new(1024);
+        let mut buf = BytesMut::from(&HANDSHAKE[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(handshake()));
+
+        let frame = frame(NOTIFY, &[1, 2, 3]);
+        buf.extend_from_slice(&frame[..HEADER_LENGTH + 2]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&frame[HEADER_LENGTH + 2..]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(EvseFrame::Message {
+                message_type: NOTIFY,
+                payload: Bytes::from_static(&[1, 2, 3]),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_frames_above_max_frame_size() {
+        let mut codec = EvseCodec::new(16);
+        let mut buf = BytesMut::from(&HANDSHAKE[..]);
+        codec.decode(&mut buf).unwrap();
+
+        buf.extend_from_slice(&frame(NOTIFY, &[0; 16]));
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        // the header alone is enough to reject the frame
+        buf.extend_from_slice(&frame(NOTIFY, &[0; 17])[..HEADER_LENGTH]);
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn encodes_requests() {
+        let mut codec = EvseCodec::new(1024);
+        let mut buf = BytesMut::new();
+        codec
+            .encode(
+                MqttMessage::new(MqttMessageType::request_ping, "evse".to_string()),
+                &mut buf,
+            )
+            .unwrap();
+        codec
+            .encode(
+                MqttMessage {
+                    pwm_percent: Some(16),
+                    ..MqttMessage::new(MqttMessageType::request_set_pwm_percent, "evse".to_string())
+                },
+                &mut buf,
+            )
+            .unwrap();
+        assert_eq!(
+            &buf[..],
+            &[
+                REQUEST_TYPE_PING,
+                0,
+                0,
+                0,
+                0,
+                REQUEST_TYPE_SET_PWM_PERCENT,
+                0,
+                0,
+                0,
+                1,
+                16,
+            ]
+        );
+    }
+
+    fn collect_data_payload() -> Vec<u8> {
+        let mut payload = vec![1, 16, 2, 2];
+        for value in [230_000u32, 231_000, 229_000, 9_600, 9_500, 0] {
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        payload.extend_from_slice(&(-70i32).to_be_bytes());
+        payload.extend_from_slice(&42i32.to_be_bytes());
+        payload.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2]);
+        payload.extend_from_slice(b"log");
+        payload
+    }
+
+    #[test]
+    fn decodes_collect_data() {
+        let msg = evse_to_mqtt(
+            "evse".to_string(),
+            RESPONSE_TYPE_COLLECT_DATA,
+            &collect_data_payload(),
+        )
+        .unwrap();
+        assert_eq!(msg.contactor_state, Some(true));
+        assert_eq!(msg.pwm_percent, Some(16));
+        let measurements = msg.measurements.unwrap();
+        assert_eq!(measurements.pilot_voltage, PilotVoltage::volt_6);
+        assert_eq!(measurements.cable_max_amps, Some(32));
+        assert_eq!(measurements.phase2_millivolts, 231_000);
+        assert_eq!(measurements.phase1_milliamps, 9_600);
+        assert_eq!(measurements.wifi_rssi, -70);
+        assert_eq!(measurements.current_proximity_pilot_adc, 2);
+        assert_eq!(measurements.logging_buffer, "log");
+    }
+
+    #[test]
+    fn reports_short_collect_data() {
+        let payload = collect_data_payload();
+        for length in [0, 3, 10, 43] {
+            let err = evse_to_mqtt(
+                "evse".to_string(),
+                RESPONSE_TYPE_COLLECT_DATA,
+                &payload[..length],
+            )
+            .unwrap_err();
+            assert_eq!(err.actual_length, length);
+            assert!(err.expected_length.unwrap() > length);
+        }
+        let err = evse_to_mqtt(
+            "evse".to_string(),
+            RESPONSE_TYPE_COLLECT_DATA,
+            &payload[..10],
+        )
+        .unwrap_err();
+        assert_eq!(err.field, "phase2_millivolts");
+        assert_eq!(err.expected_length, Some(12));
+    }
+
+    #[test]
+    fn reports_unsupported_values() {
+        let mut payload = collect_data_payload();
+        payload[2] = 9;
+        let err =
+            evse_to_mqtt("evse".to_string(), RESPONSE_TYPE_COLLECT_DATA, &payload).unwrap_err();
+        assert_eq!(err.field, "pilot_voltage");
+        assert_eq!(err.expected_length, None);
+    }
+
+    #[test]
+    fn converts_amps_to_pwm_percent() {
+        for (amps, percent) in [
+            (0.0, 100),
+            (6.0, 10),
+            (16.0, 26),
+            (32.0, 53),
+            (51.0, 85),
+            // 52A would be 84% above 51A, which is clamped to stay in the upper range
+            (52.0, 85),
+            (55.0, 86),
+            (80.0, 96),
+        ] {
+            assert_eq!(amps_to_pwm_percent(amps).unwrap(), percent, "{}A", amps);
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_currents() {
+        for amps in [-6.0, 0.1, 5.9, 80.1, 100.0, f32::NAN, f32::INFINITY] {
+            assert!(amps_to_pwm_percent(amps).is_err(), "{}A", amps);
+        }
+    }
+
+    #[test]
+    fn converts_pwm_percent_to_amps() {
+        for (percent, amps) in [(10, 6.0), (53, 31.8), (85, 51.0), (86, 55.0), (96, 80.0)] {
+            assert!(
+                (pwm_percent_to_amps(percent) - amps).abs() < 0.001,
+                "{}%",
+                percent
+            );
+        }
+        for percent in [0, 5, 9, 97, 99, 100] {
+            assert_eq!(pwm_percent_to_amps(percent), 0.0, "{}%", percent);
+        }
+    }
+
+    #[test]
+    fn never_signals_more_than_requested() {
+        for deciamps in 60..=800 {
+            let amps = deciamps as f32 / 10.0;
+            let signalled = pwm_percent_to_amps(amps_to_pwm_percent(amps).unwrap());
+            assert!(
+                signalled <= amps + 0.001,
+                "{}A signalled as {}A",
+                amps,
+                signalled
+            );
+            // 85% (51A) and 86% (55A) are the coarsest steps
+            assert!(
+                signalled > amps - 4.0,
+                "{}A signalled as {}A",
+                amps,
+                signalled
+            );
+        }
+    }
+}
@@ -0,0 +1,379 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use config::Config;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+use crate::protocol::{MqttMessage, MqttMessageHandshake};
+use crate::utils::evse_setting;
+
+/// Why a command could not be delivered to a charging station.
+#[derive(Debug)]
+pub enum RouteError {
+    NotConnected,
+    /// The connection has not caught up with the commands sent to it before
+    Full,
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::NotConnected => write!(f, "charging station is not connected"),
+            RouteError::Full => write!(f, "charging station is not keeping up with its commands"),
+        }
+    }
+}
+
+/// Where a command for a charging station comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandOrigin {
+    /// A controller publishing to MQTT
+    Mqtt,
+    /// A task of the bridge itself, e.g. load balancing or the planner
+    Bridge,
+}
+
+/// What to do when a charging station connects while a connection with the same `client_id` is
+/// still registered, e.g. because it reconnected before the old socket timed out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicatePolicy {
+    /// The newest connection wins, the previous one is closed
+    Replace,
+    /// The new connection is closed
+    Reject,
+}
+
+impl DuplicatePolicy {
+    pub fn from_settings(settings: &Config, client_serial: &str) -> Result<DuplicatePolicy, Error> {
+        let policy: String = evse_setting(settings, client_serial, "duplicate_connection")
+            .unwrap_or_else(|_| "replace".to_string());
+        match policy.as_str() {
+            "replace" => Ok(DuplicatePolicy::Replace),
+            "reject" => Ok(DuplicatePolicy::Reject),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Unsupported duplicate_connection={}, expected replace or reject",
+                    policy
+                ),
+            )),
+        }
+    }
+}
+
+struct Connection {
+    id: u64,
+    peer_addr: SocketAddr,
+    handshake: MqttMessageHandshake,
+    commands_tx: mpsc::Sender<(MqttMessage, CommandOrigin)>,
+}
+
+#[derive(Default)]
+struct Connections {
+    next_id: u64,
+    by_client_id: HashMap<String, Connection>,
+}
+
+/// The charging stations connected to the bridge, each with the command channel of its
+/// connection. Commands are only delivered to their target, so a connection not keeping up
+/// loses its own commands without delaying the others.
+#[derive(Clone)]
+pub struct ConnectionRegistry {
+    connections: Arc<Mutex<Connections>>,
+    capacity: usize,
+}
+
+impl ConnectionRegistry {
+    /// `capacity` commands may wait per connection.
+    pub fn new(capacity: usize) -> ConnectionRegistry {
+        ConnectionRegistry {
+            connections: Arc::new(Mutex::new(Connections::default())),
+            capacity,
+        }
+    }
+
+    /// Registers the connection of a charging station, which receives its commands until the
+    /// returned registration is dropped. If a connection with the same `client_id` is registered
+    /// already, either the new connection is refused with the address of the registered one, or
+    /// the registered one is replaced and its address returned along with the registration. The
+    /// command channel of a replaced connection is closed.
+    pub fn register(
+        &self,
+        client_id: &str,
+        peer_addr: SocketAddr,
+        handshake: MqttMessageHandshake,
+        policy: DuplicatePolicy,
+    ) -> Result<(Registration, Option<SocketAddr>), SocketAddr> {
+        let mut connections = self.connections.lock().unwrap();
+        let registered = connections
+            .by_client_id
+            .get(client_id)
+            .map(|connection| connection.peer_addr);
+        if let (Some(registered), DuplicatePolicy::Reject) = (registered, policy) {
+            return Err(registered);
+        }
+
+        let (commands_tx, commands) = mpsc::channel(self.capacity);
+        let id = connections.next_id;
+        connections.next_id += 1;
+        connections.by_client_id.insert(
+            client_id.to_string(),
+            Connection {
+                id,
+                peer_addr,
+                handshake,
+                commands_tx,
+            },
+        );
+        let registration = Registration {
+            registry: self.clone(),
+            client_id: client_id.to_string(),
+            id,
+            commands,
+        };
+        Ok((registration, registered))
+    }
+
+    /// The `client_id`s of all registered connections.
+    pub fn client_ids(&self) -> HashSet<String> {
+        let connections = self.connections.lock().unwrap();
+        connections.by_client_id.keys().cloned().collect()
+    }
+
+    /// The handshakes of all registered connections by `client_id`, for tasks which missed
+    /// their `new_connection` messages.
+    pub fn handshakes(&self) -> HashMap<String, MqttMessageHandshake> {
+        let connections = self.connections.lock().unwrap();
+        connections
+            .by_client_id
+            .iter()
+            .map(|(client_id, connection)| (client_id.clone(), connection.handshake.clone()))
+            .collect()
+    }
+
+    /// Delivers a command to the connection of `msg.client_id` without waiting.
+    pub fn send(&self, msg: MqttMessage, origin: CommandOrigin) -> Result<(), RouteError> {
+        let connections = self.connections.lock().unwrap();
+        let connection = connections
+            .by_client_id
+            .get(&msg.client_id)
+            .ok_or(RouteError::NotConnected)?;
+        connection
+            .commands_tx
+            .try_send((msg, origin))
+            .map_err(|err| match err {
+                TrySendError::Full(_) => RouteError::Full,
+                TrySendError::Closed(_) => RouteError::NotConnected,
+            })
+    }
+
+    fn unregister(&self, client_id: &str, id: u64) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        if matches!(connections.by_client_id.get(client_id), Some(connection) if connection.id == id)
+        {
+            connections.by_client_id.remove(client_id);
+            return true;
+        }
+        false
+    }
+}
+
+/// The registration of a connection, removed from the registry when dropped.
+pub struct Registration {
+    registry: ConnectionRegistry,
+    client_id: String,
+    id: u64,
+    /// Commands for the charging station
+    pub commands: mpsc::Receiver<(MqttMessage, CommandOrigin)>,
+}
+
+impl Registration {
+    /// Removes the connection from the registry, returning whether it was still registered,
+    /// i.e. not replaced by a newer connection of the same charging station.
+    pub fn unregister(self) -> bool {
+        self.registry.unregister(&self.client_id, self.id)
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.unregister(&self.client_id, self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::EvseLimits;
+    use crate::protocol::MqttMessageType;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 168, 0, 10], port))
+    }
+
+    fn handshake() -> MqttMessageHandshake {
+        MqttMessageHandshake {
+            serial: "10BA23AB".to_string(),
+            firmware_version: 7,
+            limits: EvseLimits::from_settings(&Config::default(), "10BA23AB"),
+        }
+    }
+
+    fn register(
+        registry: &ConnectionRegistry,
+        client_id: &str,
+        port: u16,
+        policy: DuplicatePolicy,
+    ) -> std::result::Result<(Registration, Option<SocketAddr>), SocketAddr> {
+        registry.register(client_id, addr(port), handshake(), policy)
+    }
+
+    fn ping(client_id: &str) -> MqttMessage {
+        MqttMessage::new(MqttMessageType::request_ping, client_id.to_string())
+    }
+
+    #[test]
+    fn delivers_commands_to_their_connection() {
+        let registry = ConnectionRegistry::new(4);
+        let (mut a, _) = register(&registry, "a", 1, DuplicatePolicy::Replace).unwrap();
+        let (mut b, _) = register(&registry, "b", 2, DuplicatePolicy::Replace).unwrap();
+
+        registry.send(ping("b"), CommandOrigin::Bridge).unwrap();
+        let (msg, origin) = b.commands.try_recv().unwrap();
+        assert_eq!(msg.client_id, "b");
+        assert_eq!(origin, CommandOrigin::Bridge);
+        assert!(a.commands.try_recv().is_err());
+
+        assert_eq!(
+            registry.client_ids(),
+            HashSet::from(["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(registry.handshakes()["a"].serial, "10BA23AB");
+    }
+
+    #[test]
+    fn reports_unknown_charging_stations() {
+        let registry = ConnectionRegistry::new(4);
+        assert!(matches!(
+            registry.send(ping("a"), CommandOrigin::Mqtt),
+            Err(RouteError::NotConnected)
+        ));
+    }
+
+    #[test]
+    fn reports_full_connections() {
+        let registry = ConnectionRegistry::new(1);
+        let (mut registration, _) = register(&registry, "a", 1, DuplicatePolicy::Replace).unwrap();
+        registry.send(ping("a"), CommandOrigin::Mqtt).unwrap();
+        assert!(matches!(
+            registry.send(ping("a"), CommandOrigin::Mqtt),
+            Err(RouteError::Full)
+        ));
+
+        // the connection catching up makes room again
+        registration.commands.try_recv().unwrap();
+        registry.send(ping("a"), CommandOrigin::Mqtt).unwrap();
+    }
+
+    #[test]
+    fn unregisters_dropped_connections() {
+        let registry = ConnectionRegistry::new(4);
+        let (registration, _) = register(&registry, "a", 1, DuplicatePolicy::Replace).unwrap();
+        drop(registration);
+        assert!(registry.client_ids().is_empty());
+        assert!(matches!(
+            registry.send(ping("a"), CommandOrigin::Mqtt),
+            Err(RouteError::NotConnected)
+        ));
+    }
+
+    #[test]
+    fn reports_whether_the_connection_was_registered() {
+        let registry = ConnectionRegistry::new(4);
+        let (registration, _) = register(&registry, "a", 1, DuplicatePolicy::Replace).unwrap();
+        assert!(registration.unregister());
+        assert!(registry.client_ids().is_empty());
+
+        // replaced before it noticed its closed channel
+        let (old, _) = register(&registry, "a", 1, DuplicatePolicy::Replace).unwrap();
+        let (_new, _) = register(&registry, "a", 2, DuplicatePolicy::Replace).unwrap();
+        assert!(!old.unregister());
+        assert_eq!(registry.client_ids(), HashSet::from(["a".to_string()]));
+    }
+
+    #[test]
+    fn keeps_the_replacing_connection_when_the_replaced_one_is_dropped() {
+        let registry = ConnectionRegistry::new(4);
+        let (mut old, _) = register(&registry, "a", 1, DuplicatePolicy::Replace).unwrap();
+        let (mut new, _) = register(&registry, "a", 2, DuplicatePolicy::Replace).unwrap();
+
+        // the replaced connection learns about it from its closed channel and must not
+        // publish connection_closed
+        assert!(old.commands.blocking_recv().is_none());
+        assert!(!old.unregister());
+
+        assert_eq!(registry.client_ids(), HashSet::from(["a".to_string()]));
+        registry.send(ping("a"), CommandOrigin::Mqtt).unwrap();
+        assert!(new.commands.try_recv().is_ok());
+    }
+
+    #[test]
+    fn replaces_registered_connections() {
+        let registry = ConnectionRegistry::new(4);
+        let (mut old, previous) = register(&registry, "a", 1, DuplicatePolicy::Replace).unwrap();
+        assert_eq!(previous, None);
+        let (mut new, previous) = register(&registry, "a", 2, DuplicatePolicy::Replace).unwrap();
+        assert_eq!(previous, Some(addr(1)));
+
+        assert!(old.commands.blocking_recv().is_none());
+        registry.send(ping("a"), CommandOrigin::Mqtt).unwrap();
+        assert!(new.commands.try_recv().is_ok());
+    }
+
+    #[test]
+    fn rejects_duplicate_connections() {
+        let registry = ConnectionRegistry::new(4);
+        let (mut registered, _) = register(&registry, "a", 1, DuplicatePolicy::Reject).unwrap();
+        assert_eq!(
+            register(&registry, "a", 2, DuplicatePolicy::Reject).err(),
+            Some(addr(1))
+        );
+
+        // the registered connection keeps receiving its commands
+        registry.send(ping("a"), CommandOrigin::Mqtt).unwrap();
+        assert!(registered.commands.try_recv().is_ok());
+
+        // and can be replaced once it is gone
+        drop(registered);
+        let (_, previous) = register(&registry, "a", 2, DuplicatePolicy::Reject).unwrap();
+        assert_eq!(previous, None);
+    }
+
+    #[test]
+    fn reads_the_duplicate_policy() {
+        let settings = Config::builder()
+            .set_override("evse.id_10BA23AB.duplicate_connection", "reject")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(
+            DuplicatePolicy::from_settings(&settings, "10BA23AB").unwrap(),
+            DuplicatePolicy::Reject
+        );
+        assert_eq!(
+            DuplicatePolicy::from_settings(&settings, "10BA23AC").unwrap(),
+            DuplicatePolicy::Replace
+        );
+
+        let settings = Config::builder()
+            .set_override("evse.duplicate_connection", "ignore")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(DuplicatePolicy::from_settings(&settings, "10BA23AB").is_err());
+    }
+}
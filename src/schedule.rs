@@ -0,0 +1,173 @@
+use std::{
+    error,
+    io::{Error, ErrorKind},
+};
+
+use chrono::{Datelike, Duration, NaiveDateTime, NaiveTime};
+use config::Config;
+
+use crate::utils::evse_setting;
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// A time of the day in which charging is allowed, ending on the next day if `end` is not
+/// after `start`.
+struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window {
+    fn parse(window: &str) -> Result<Window> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid charging window {}, expected HH:MM-HH:MM", window),
+            )
+        };
+        let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+        Ok(Window {
+            start: NaiveTime::parse_from_str(start.trim(), "%H:%M").map_err(|_| invalid())?,
+            end: NaiveTime::parse_from_str(end.trim(), "%H:%M").map_err(|_| invalid())?,
+        })
+    }
+
+    fn spans_midnight(&self) -> bool {
+        self.end <= self.start
+    }
+}
+
+/// Weekly charging windows of a charging station, configured in `[ evse.schedule ]` or
+/// `[ evse.id_<serial>.schedule ]`.
+pub struct Schedule {
+    /// Charge current inside the windows, unless a different one has been requested
+    pub current: f32,
+    /// Windows per weekday, starting with monday
+    windows: Vec<Vec<Window>>,
+}
+
+impl Schedule {
+    pub fn from_settings(settings: &Config, client_serial: &str) -> Result<Option<Schedule>> {
+        if !evse_setting(settings, client_serial, "schedule.enabled").unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let daily: Vec<String> =
+            evse_setting(settings, client_serial, "schedule.daily").unwrap_or_default();
+        let mut windows = vec![];
+        for weekday in WEEKDAYS {
+            let day: Vec<String> =
+                evse_setting(settings, client_serial, &format!("schedule.{}", weekday))
+                    .unwrap_or_else(|_| daily.clone());
+            windows.push(
+                day.iter()
+                    .map(|window| Window::parse(window))
+                    .collect::<Result<Vec<Window>>>()?,
+            );
+        }
+
+        Ok(Some(Schedule {
+            current: evse_setting(settings, client_serial, "schedule.current").unwrap_or(16.0),
+            windows,
+        }))
+    }
+
+    /// Whether charging is allowed at the given local time.
+    pub fn is_open(&self, now: NaiveDateTime) -> bool {
+        let today = now.weekday().num_days_from_monday() as usize;
+        let yesterday = (now - Duration::days(1)).weekday().num_days_from_monday() as usize;
+        let time = now.time();
+
+        self.windows[today].iter().any(|w| {
+            if w.spans_midnight() {
+                time >= w.start
+            } else {
+                w.start <= time && time < w.end
+            }
+        }) || self.windows[yesterday]
+            .iter()
+            .any(|w| w.spans_midnight() && time < w.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn schedule(windows: &[(&str, &[&str])]) -> Schedule {
+        let mut builder = Config::builder().set_override("evse.schedule.enabled", true).unwrap();
+        for (key, value) in windows {
+            let value: Vec<String> = value.iter().map(|w| w.to_string()).collect();
+            builder = builder
+                .set_override(format!("evse.schedule.{}", key), value)
+                .unwrap();
+        }
+        Schedule::from_settings(&builder.build().unwrap(), "serial")
+            .unwrap()
+            .unwrap()
+    }
+
+    /// 2022-10-03 is a monday.
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2022, 10, day)
+            .unwrap()
+            .and_time(NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    #[test]
+    fn opens_within_a_window() {
+        let schedule = schedule(&[("daily", &["08:00-12:00"])]);
+        assert!(!schedule.is_open(at(3, "07:59")));
+        assert!(schedule.is_open(at(3, "08:00")));
+        assert!(schedule.is_open(at(3, "11:59")));
+        assert!(!schedule.is_open(at(3, "12:00")));
+    }
+
+    #[test]
+    fn continues_a_window_across_midnight() {
+        let schedule = schedule(&[("daily", &["22:00-06:00"])]);
+        assert!(!schedule.is_open(at(3, "21:59")));
+        assert!(schedule.is_open(at(3, "22:00")));
+        assert!(schedule.is_open(at(4, "00:00")));
+        assert!(schedule.is_open(at(4, "05:59")));
+        assert!(!schedule.is_open(at(4, "06:00")));
+    }
+
+    #[test]
+    fn ends_a_window_of_the_previous_weekday() {
+        // only friday night, which continues on saturday morning
+        let schedule = schedule(&[("daily", &[]), ("fri", &["22:00-06:00"])]);
+        assert!(!schedule.is_open(at(6, "23:00")));
+        assert!(!schedule.is_open(at(7, "05:00")));
+        assert!(schedule.is_open(at(7, "23:00")));
+        assert!(schedule.is_open(at(8, "05:00")));
+        assert!(!schedule.is_open(at(8, "23:00")));
+        assert!(!schedule.is_open(at(9, "05:00")));
+    }
+
+    #[test]
+    fn overrides_daily_windows_per_weekday() {
+        let schedule = schedule(&[("daily", &["22:00-06:00"]), ("sun", &["00:00-00:00"])]);
+        // all of sunday, ending at midnight instead of monday 06:00
+        assert!(schedule.is_open(at(9, "00:00")));
+        assert!(schedule.is_open(at(9, "12:00")));
+        assert!(schedule.is_open(at(9, "23:59")));
+        assert!(!schedule.is_open(at(10, "05:00")));
+        assert!(schedule.is_open(at(10, "22:00")));
+    }
+
+    #[test]
+    fn rejects_invalid_windows() {
+        let settings = Config::builder()
+            .set_override("evse.schedule.enabled", true)
+            .unwrap()
+            .set_override("evse.schedule.daily", vec!["22:00"])
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(Schedule::from_settings(&settings, "serial").is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::error;
+use std::time::Duration;
+
+use chrono::{DateTime, Local, Utc};
+use config::Config;
+use log::{error, info};
+use tokio::sync::broadcast;
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::history::SessionHistory;
+use crate::protocol::{
+    ErrorCode, MqttMessage, MqttMessageMeasurements, MqttMessageRejection, MqttMessageSession,
+    MqttMessageSessionHistory, MqttMessageType, PilotVoltage, ProximityPilotAmps,
+};
+use crate::utils::energy_kwh;
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+/// A vehicle plugged into a charging station. Sessions are kept across reconnects of the
+/// charging station, the energy charged while it is not connected is not counted though.
+struct Session {
+    started: DateTime<Utc>,
+    energy_kwh: f32,
+    peak_current: f32,
+    /// Power and time of the last data collection
+    last: Option<(f32, Instant)>,
+}
+
+impl Session {
+    fn update(&mut self, measurements: &MqttMessageMeasurements) {
+        let now = Instant::now();
+        if let Some((power, measured)) = self.last {
+            self.energy_kwh +=
+                energy_kwh(power, measurements.power(), now.duration_since(measured)) as f32;
+        }
+        self.last = Some((measurements.power(), now));
+        self.peak_current = self.peak_current.max(measurements.max_current());
+    }
+
+    fn to_message(&self, ended: Option<DateTime<Utc>>) -> MqttMessageSession {
+        let end = ended.unwrap_or_else(Utc::now);
+        MqttMessageSession {
+            started: self.started.with_timezone(&Local).to_rfc3339(),
+            ended: ended.map(|ended| ended.with_timezone(&Local).to_rfc3339()),
+            duration_seconds: (end - self.started).num_seconds().max(0) as u64,
+            energy_kwh: self.energy_kwh,
+            peak_current: self.peak_current,
+        }
+    }
+}
+
+/// Whether a vehicle is plugged in: a cable is detected and the control pilot is in state B, C
+/// or D. Faults leave it undecided.
+fn is_plugged_in(measurements: &MqttMessageMeasurements) -> Option<bool> {
+    match measurements.pilot_voltage {
+        PilotVoltage::fault => None,
+        _ if measurements.proximity_pilot_amps == ProximityPilotAmps::no_cable => Some(false),
+        PilotVoltage::volt_12 => Some(false),
+        PilotVoltage::volt_9 | PilotVoltage::volt_6 | PilotVoltage::volt_3 => Some(true),
+    }
+}
+
+/// Tracks the charging sessions of all charging stations from their data collections,
+/// publishing `session_started` when a vehicle is plugged in, `session_progress` every
+/// `sessions.progress_interval_seconds` and `session_ended` when it is unplugged. Ended sessions
+/// are stored in the session history, which is queried with `request_session_history`, as are
+/// the open sessions on shutdown.
+pub async fn handle_sessions(
+    mut bridge_request_rx: broadcast::Receiver<MqttMessage>,
+    evse_mqtt_tx: broadcast::Sender<MqttMessage>,
+    mut evse_mqtt_rx: broadcast::Receiver<MqttMessage>,
+    mut shutdown_rx: broadcast::Receiver<bool>,
+    settings: Config,
+) -> Result<()> {
+    let mut progress = tokio::time::interval(Duration::from_secs(
+        settings
+            .get_int("sessions.progress_interval_seconds")
+            .unwrap_or(60) as u64,
+    ));
+    progress.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let history = SessionHistory::from_settings(&settings);
+    let mut sessions: HashMap<String, Session> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            Ok(_) = shutdown_rx.recv() => {
+                break;
+            }
+            receive = evse_mqtt_rx.recv() => {
+                let msg = match receive {
+                    Err(broadcast::error::RecvError::Closed) => break,
+                    Err(_) => continue,
+                    Ok(msg) => msg,
+                };
+                let measurements = match (&msg.message_type, &msg.measurements) {
+                    (MqttMessageType::response_collect_data, Some(measurements)) => measurements,
+                    (MqttMessageType::connection_closed, _) => {
+                        if let Some(session) = sessions.get_mut(&msg.client_id) {
+                            session.last = None;
+                        }
+                        continue;
+                    }
+                    _ => continue,
+                };
+
+                match (is_plugged_in(measurements), sessions.get_mut(&msg.client_id)) {
+                    (Some(true), Some(session)) => session.update(measurements),
+                    (Some(true), None) => {
+                        info!("SESSION: Vehicle plugged in at {}", msg.client_id);
+                        let mut session = Session {
+                            started: Utc::now(),
+                            energy_kwh: 0.0,
+                            peak_current: 0.0,
+                            last: None,
+                        };
+                        session.update(measurements);
+                        evse_mqtt_tx.send(MqttMessage {
+                            session: Some(session.to_message(None)),
+                            ..MqttMessage::new(MqttMessageType::session_started, msg.client_id.clone())
+                        })?;
+                        sessions.insert(msg.client_id, session);
+                    }
+                    (Some(false), Some(session)) => {
+                        session.update(measurements);
+                        let session = session.to_message(Some(Utc::now()));
+                        info!(
+                            "SESSION: Vehicle unplugged at {} after {}s with {}kWh charged",
+                            msg.client_id, session.duration_seconds, session.energy_kwh
+                        );
+                        sessions.remove(&msg.client_id);
+                        if let Err(err) = history.append(&msg.client_id, &session).await {
+                            error!("SESSION: Could not store the session of {}: {}", msg.client_id, err);
+                        }
+                        evse_mqtt_tx.send(MqttMessage {
+                            session: Some(session),
+                            ..MqttMessage::new(MqttMessageType::session_ended, msg.client_id)
+                        })?;
+                    }
+                    _ => {}
+                }
+            }
+            Ok(msg) = bridge_request_rx.recv() => {
+                if let (MqttMessageType::request_session_history, Some(range)) = (&msg.message_type, &msg.session_history) {
+                    let sessions = match (DateTime::parse_from_rfc3339(&range.from), DateTime::parse_from_rfc3339(&range.to)) {
+                        (Ok(from), Ok(to)) => history.query(&msg.client_id, from, to).await.map_err(|err| err.to_string()),
+                        (Err(err), _) | (_, Err(err)) => Err(format!("invalid date range: {}", err)),
+                    };
+                    let response = match sessions {
+                        Ok(sessions) => MqttMessage {
+                            session_history: Some(MqttMessageSessionHistory {
+                                energy_kwh: Some(sessions.iter().map(|s| s.energy_kwh).sum()),
+                                sessions,
+                                ..range.clone()
+                            }),
+                            ..MqttMessage::new(MqttMessageType::response_session_history, msg.client_id)
+                        },
+                        Err(reason) => MqttMessage {
+                            rejection: Some(MqttMessageRejection {
+                                request_type: msg.message_type,
+                                reason,
+                            }),
+                            ..MqttMessage::new(MqttMessageType::command_rejected, msg.client_id)
+                        },
+                    };
+                    evse_mqtt_tx.send(MqttMessage { request_id: msg.request_id, ..response })?;
+                } else if msg.message_type == MqttMessageType::request_session_history {
+                    evse_mqtt_tx.send(MqttMessage::error_for(&msg, ErrorCode::invalid_request, "session_history cannot be null".to_string()))?;
+                }
+            }
+            _ = progress.tick() => {
+                for (client_id, session) in &sessions {
+                    evse_mqtt_tx.send(MqttMessage {
+                        session: Some(session.to_message(None)),
+                        ..MqttMessage::new(MqttMessageType::session_progress, client_id.clone())
+                    })?;
+                }
+            }
+        }
+    }
+
+    // the sessions end with the bridge, a vehicle still plugged in starts a new one after a restart
+    for (client_id, session) in sessions {
+        let session = session.to_message(Some(Utc::now()));
+        info!("SESSION: Storing the session at {} on shutdown with {}kWh charged", client_id, session.energy_kwh);
+        if let Err(err) = history.append(&client_id, &session).await {
+            error!("SESSION: Could not store the session of {}: {}", client_id, err);
+        }
+    }
+
+    Ok(())
+}
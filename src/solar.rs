@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::error;
+use std::time::Duration;
+
+use config::Config;
+use log::{error, info};
+use tokio::time::Instant;
+
+use crate::mqtt_handler::MqttInput;
+use crate::protocol::ChargeMode;
+use crate::utils::read_number;
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+/// A charging station in one of the solar charge modes, as seen by [`Solar::limits`].
+pub struct SolarCharger<'a> {
+    pub client_id: &'a str,
+    pub charge_mode: ChargeMode,
+    pub min_current: f32,
+    /// Number of phases the vehicle draws (or would draw) from
+    pub phases: usize,
+    /// Average voltage of these phases
+    pub voltage: f32,
+    /// Power currently drawn by the vehicle in watts
+    pub power: f32,
+}
+
+/// Whether a charging station in `solar_only` is charging, and since when the surplus
+/// suggests to change that.
+#[derive(Default)]
+struct SolarState {
+    charging: bool,
+    change_since: Option<Instant>,
+}
+
+/// Turns the PV surplus published to MQTT into charge currents for the charging stations in
+/// one of the solar charge modes. The surplus is read in watts, positive meaning export.
+pub struct Solar {
+    topic: String,
+    json_pointer: Option<String>,
+    invert: bool,
+    hysteresis: f32,
+    start_delay: Duration,
+    stop_delay: Duration,
+    stale_after: Duration,
+    surplus: Option<(f32, Instant)>,
+    states: HashMap<String, SolarState>,
+}
+
+impl Solar {
+    pub fn from_settings(settings: &Config) -> Result<Option<Solar>> {
+        let topic = match settings.get_string("load_balancing.solar.topic") {
+            Ok(topic) => topic,
+            Err(_) => return Ok(None),
+        };
+        let seconds = |key: &str, default: i64| {
+            Duration::from_secs(
+                settings
+                    .get_int(&format!("load_balancing.solar.{}", key))
+                    .unwrap_or(default) as u64,
+            )
+        };
+
+        Ok(Some(Solar {
+            topic,
+            json_pointer: settings.get_string("load_balancing.solar.json_pointer").ok(),
+            invert: settings.get_bool("load_balancing.solar.invert").unwrap_or(false),
+            hysteresis: settings
+                .get_float("load_balancing.solar.hysteresis")
+                .unwrap_or(1.0) as f32,
+            start_delay: seconds("start_delay_seconds", 60),
+            stop_delay: seconds("stop_delay_seconds", 300),
+            stale_after: seconds("stale_seconds", 60),
+            surplus: None,
+            states: HashMap::new(),
+        }))
+    }
+
+    pub fn update(&mut self, input: &MqttInput) {
+        if input.topic != self.topic {
+            return;
+        }
+        match read_number(&input.payload, self.json_pointer.as_deref()) {
+            Some(watts) if self.invert => self.surplus = Some((-watts as f32, Instant::now())),
+            Some(watts) => self.surplus = Some((watts as f32, Instant::now())),
+            None => error!(
+                "LOAD: Could not read the PV surplus from {}: {}",
+                input.topic, input.payload
+            ),
+        }
+    }
+
+    pub fn remove(&mut self, client_id: &str) {
+        self.states.remove(client_id);
+    }
+
+    /// The highest charge current for each of the given charging stations, served from the
+    /// surplus in the given order. A stale surplus counts as none.
+    ///
+    /// `solar_plus_min` always gets at least its minimum current. `solar_only` starts once the
+    /// surplus covers its minimum current for `start_delay`, and stops once the surplus has been
+    /// more than `hysteresis` below its minimum current for `stop_delay`; in between it keeps
+    /// charging with its minimum current.
+    pub fn limits(&mut self, chargers: &[SolarCharger]) -> Vec<f32> {
+        let now = Instant::now();
+        let surplus = match self.surplus {
+            Some((watts, updated)) if updated.elapsed() < self.stale_after => watts,
+            _ => 0.0,
+        };
+        // what the vehicles draw already shows up as less export
+        let mut remaining = (surplus + chargers.iter().map(|c| c.power).sum::<f32>()).max(0.0);
+
+        let mut limits = vec![];
+        for charger in chargers {
+            let watts_per_amp = charger.voltage * charger.phases as f32;
+            let amps = remaining / watts_per_amp;
+
+            let limit = match charger.charge_mode {
+                ChargeMode::fast => f32::MAX,
+                ChargeMode::solar_plus_min => amps.max(charger.min_current),
+                ChargeMode::solar_only => {
+                    let state = self.states.entry(charger.client_id.to_string()).or_default();
+                    let (change, delay) = if state.charging {
+                        (amps < charger.min_current - self.hysteresis, self.stop_delay)
+                    } else {
+                        (amps >= charger.min_current, self.start_delay)
+                    };
+                    if !change {
+                        state.change_since = None;
+                    } else if now.duration_since(*state.change_since.get_or_insert(now)) >= delay {
+                        state.charging = !state.charging;
+                        state.change_since = None;
+                        info!(
+                            "LOAD: {} solar charging of {} ({}A available)",
+                            if state.charging { "Starting" } else { "Stopping" },
+                            charger.client_id,
+                            amps
+                        );
+                    }
+
+                    if state.charging {
+                        amps.max(charger.min_current)
+                    } else {
+                        0.0
+                    }
+                }
+            };
+
+            if limit != f32::MAX {
+                remaining = (remaining - limit * watts_per_amp).max(0.0);
+            }
+            limits.push(limit);
+        }
+        limits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Starts and stops `solar_only` without delay.
+    fn solar(surplus: &str) -> Solar {
+        let settings = Config::builder()
+            .set_override("load_balancing.solar.topic", "pv")
+            .unwrap()
+            .set_override("load_balancing.solar.start_delay_seconds", 0)
+            .unwrap()
+            .set_override("load_balancing.solar.stop_delay_seconds", 0)
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut solar = Solar::from_settings(&settings).unwrap().unwrap();
+        set_surplus(&mut solar, surplus);
+        solar
+    }
+
+    fn set_surplus(solar: &mut Solar, surplus: &str) {
+        solar.update(&MqttInput {
+            topic: "pv".to_string(),
+            payload: surplus.to_string(),
+        });
+    }
+
+    fn charger(
+        client_id: &str,
+        charge_mode: ChargeMode,
+        phases: usize,
+        power: f32,
+    ) -> SolarCharger<'_> {
+        SolarCharger {
+            client_id,
+            charge_mode,
+            min_current: 6.0,
+            phases,
+            voltage: 230.0,
+            power,
+        }
+    }
+
+    #[test]
+    fn serves_the_surplus_in_order() {
+        // 10A on three phases
+        let mut solar = solar("6900");
+        let limits = solar.limits(&[
+            charger("a", ChargeMode::solar_plus_min, 3, 0.0),
+            charger("b", ChargeMode::solar_plus_min, 3, 0.0),
+        ]);
+        assert_eq!(limits, vec![10.0, 6.0]);
+    }
+
+    #[test]
+    fn converts_the_surplus_with_the_phases_in_use() {
+        let mut solar = solar("2300");
+        let limits = solar.limits(&[
+            charger("a", ChargeMode::solar_plus_min, 1, 0.0),
+            charger("b", ChargeMode::solar_plus_min, 3, 0.0),
+        ]);
+        assert_eq!(limits, vec![10.0, 6.0]);
+    }
+
+    #[test]
+    fn adds_back_what_the_vehicles_draw() {
+        // the vehicle draws 6A on three phases, leaving 4A of export
+        let mut solar = solar("2760");
+        let limits = solar.limits(&[charger("a", ChargeMode::solar_plus_min, 3, 4140.0)]);
+        assert_eq!(limits, vec![10.0]);
+    }
+
+    #[test]
+    fn inverts_the_grid_power() {
+        let mut solar = solar("0");
+        solar.invert = true;
+        set_surplus(&mut solar, "-6900");
+        let limits = solar.limits(&[charger("a", ChargeMode::solar_plus_min, 3, 0.0)]);
+        assert_eq!(limits, vec![10.0]);
+    }
+
+    #[test]
+    fn starts_and_stops_solar_only_with_hysteresis() {
+        let mut solar = solar("2760");
+        // 4A is not enough to start
+        assert_eq!(solar.limits(&[charger("a", ChargeMode::solar_only, 3, 0.0)]), vec![0.0]);
+
+        set_surplus(&mut solar, "4830");
+        assert_eq!(solar.limits(&[charger("a", ChargeMode::solar_only, 3, 0.0)]), vec![7.0]);
+
+        // 5.5A is within the hysteresis, charging continues with the minimum current
+        set_surplus(&mut solar, "3795");
+        assert_eq!(solar.limits(&[charger("a", ChargeMode::solar_only, 3, 0.0)]), vec![6.0]);
+
+        set_surplus(&mut solar, "2760");
+        assert_eq!(solar.limits(&[charger("a", ChargeMode::solar_only, 3, 0.0)]), vec![0.0]);
+    }
+
+    #[test]
+    fn delays_starting_solar_only() {
+        let mut solar = solar("6900");
+        solar.start_delay = Duration::from_secs(60);
+        assert_eq!(solar.limits(&[charger("a", ChargeMode::solar_only, 3, 0.0)]), vec![0.0]);
+    }
+
+    #[test]
+    fn counts_a_missing_surplus_as_none() {
+        let mut solar = solar("not a number");
+        let limits = solar.limits(&[
+            charger("a", ChargeMode::solar_only, 3, 0.0),
+            charger("b", ChargeMode::solar_plus_min, 3, 0.0),
+        ]);
+        assert_eq!(limits, vec![0.0, 6.0]);
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    error,
+    io::{Error, ErrorKind},
+};
+
+use config::Config;
+use serde_json::Value;
+
+use crate::protocol::{ErrorCode, MqttMessage, MqttMessageError, MqttMessageType};
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+/// The MQTT topics used to talk to the outside world.
+#[derive(Debug, Clone)]
+pub enum Topics {
+    /// Legacy layout: everything is published on one topic and all commands are received on
+    /// another, the charging station is identified by the `client_id` inside the JSON.
+    Single { subscribe: String, publish: String },
+    /// One topic tree per charging station:
+    /// `<prefix>/<client_id>/state`, `<prefix>/<client_id>/event/<event>`,
+    /// `<prefix>/<client_id>/response/<response>` and `<prefix>/<client_id>/cmd/<command>`.
+    /// Errors which cannot be attributed to a charging station are published on `<prefix>/error`.
+    PerEvse { prefix: String },
+}
+
+impl Topics {
+    pub fn from_settings(settings: &Config) -> Result<Topics> {
+        let layout = settings
+            .get_string("mqtt.topic_layout")
+            .unwrap_or_else(|_| "single".to_string());
+        match layout.as_str() {
+            "single" => Ok(Topics::Single {
+                subscribe: settings.get_string("mqtt.topic_subscribe")?,
+                publish: settings.get_string("mqtt.topic_publish")?,
+            }),
+            "per_evse" => Ok(Topics::PerEvse {
+                prefix: settings
+                    .get_string("mqtt.topic_prefix")
+                    .unwrap_or_else(|_| "dehneEVSE".to_string()),
+            }),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unsupported mqtt.topic_layout={}", layout),
+            )
+            .into()),
+        }
+    }
+
+    pub fn subscription(&self) -> String {
+        match self {
+            Topics::Single { subscribe, .. } => subscribe.clone(),
+            Topics::PerEvse { prefix } => format!("{}/+/cmd/+", prefix),
+        }
+    }
+
+    pub fn publish_topic(&self, msg: &MqttMessage) -> String {
+        match self {
+            Topics::Single { publish, .. } => publish.clone(),
+            Topics::PerEvse { prefix } if msg.client_id.is_empty() => format!("{}/error", prefix),
+            Topics::PerEvse { prefix } => {
+                let message_type = format!("{:?}", msg.message_type);
+                let sub_topic = match msg.message_type {
+                    MqttMessageType::response_collect_data => "state".to_string(),
+                    _ => match message_type.strip_prefix("response_") {
+                        Some(response) => format!("response/{}", response),
+                        None => format!("event/{}", message_type),
+                    },
+                };
+                format!("{}/{}/{}", prefix, msg.client_id, sub_topic)
+            }
+        }
+    }
+
+    /// Where `request_<command>` for the given charging station is accepted.
+    pub fn command_topic(&self, client_id: &str, command: &str) -> String {
+        match self {
+            Topics::Single { subscribe, .. } => subscribe.clone(),
+            Topics::PerEvse { prefix } => format!("{}/{}/cmd/{}", prefix, client_id, command),
+        }
+    }
+
+    /// Retained `online`/`offline` presence of a charging station.
+    pub fn availability_topic(&self, client_id: &str) -> String {
+        format!("{}/{}/availability", self.base(), client_id)
+    }
+
+    /// Subscribed to for clearing the availability of charging stations left `online` by a
+    /// bridge which did not shut down orderly.
+    pub fn availability_subscription(&self) -> String {
+        format!("{}/+/availability", self.base())
+    }
+
+    /// The `client_id` of an availability topic.
+    pub fn availability_client_id<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        topic
+            .strip_prefix(self.base())
+            .and_then(|rest| rest.strip_prefix('/'))
+            .and_then(|rest| rest.strip_suffix("/availability"))
+            .filter(|client_id| !client_id.is_empty() && !client_id.contains('/'))
+    }
+
+    /// Retained copy of the last `response_collect_data` of a charging station. In the per-EVSE
+    /// layout this is the topic data collections are published on.
+    pub fn state_topic(&self, client_id: &str) -> String {
+        format!("{}/{}/state", self.base(), client_id)
+    }
+
+    fn base(&self) -> &str {
+        match self {
+            Topics::Single { publish, .. } => publish,
+            Topics::PerEvse { prefix } => prefix,
+        }
+    }
+
+    /// Turns a received MQTT message into a command for an EVSE. In the per-EVSE layout the
+    /// `client_id` and `message_type` are taken from the topic, so the payload may omit them
+    /// (or be empty altogether).
+    pub fn parse(&self, topic: &str, payload: &str) -> Result<MqttMessage> {
+        match self {
+            Topics::Single { .. } => Ok(serde_json::from_str::<MqttMessage>(payload)?),
+            Topics::PerEvse { prefix } => {
+                let (client_id, command) = split_command_topic(prefix, topic).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Unexpected command topic {}", topic),
+                    )
+                })?;
+
+                let mut json = if payload.trim().is_empty() {
+                    Value::Object(Default::default())
+                } else {
+                    serde_json::from_str::<Value>(payload)?
+                };
+                let object = json.as_object_mut().ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, "Payload must be a JSON object")
+                })?;
+                object.insert("client_id".to_string(), Value::from(client_id));
+                object.insert(
+                    "message_type".to_string(),
+                    Value::from(format!("request_{}", command)),
+                );
+
+                Ok(serde_json::from_value::<MqttMessage>(json)?)
+            }
+        }
+    }
+
+    /// An `error` answering a command which could not be parsed, with its `client_id`,
+    /// `message_type` and `request_id` as far as they can be read from the topic and payload.
+    pub fn parse_error(&self, topic: &str, payload: &str, reason: String) -> MqttMessage {
+        let json = serde_json::from_str::<Value>(payload).unwrap_or(Value::Null);
+        let field = |name: &str| json.get(name).and_then(Value::as_str).map(str::to_string);
+        let (client_id, message_type) = match self {
+            Topics::Single { .. } => (field("client_id"), field("message_type")),
+            Topics::PerEvse { prefix } => match split_command_topic(prefix, topic) {
+                Some((client_id, command)) => (
+                    Some(client_id.to_string()),
+                    Some(format!("request_{}", command)),
+                ),
+                None => (None, None),
+            },
+        };
+        let known_type = |message_type: &String| {
+            serde_json::from_value::<MqttMessageType>(Value::from(message_type.as_str())).is_ok()
+        };
+        let code = match &message_type {
+            Some(message_type) if !known_type(message_type) => ErrorCode::unknown_message_type,
+            _ => ErrorCode::invalid_json,
+        };
+
+        MqttMessage {
+            request_id: field("request_id"),
+            error: Some(MqttMessageError {
+                code,
+                message_type,
+                reason,
+            }),
+            ..MqttMessage::new(MqttMessageType::error, client_id.unwrap_or_default())
+        }
+    }
+}
+
+/// `<prefix>/<client_id>/cmd/<command>` split into the client_id and command.
+fn split_command_topic<'a>(prefix: &str, topic: &'a str) -> Option<(&'a str, &'a str)> {
+    let (client_id, command) = topic
+        .strip_prefix(prefix)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .and_then(|rest| rest.split_once("/cmd/"))?;
+    let level = |name: &str| !name.is_empty() && !name.contains('/');
+    if level(client_id) && level(command) {
+        Some((client_id, command))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single() -> Topics {
+        Topics::Single {
+            subscribe: "to_dehneEVSE".to_string(),
+            publish: "from_dehneEVSE".to_string(),
+        }
+    }
+
+    fn per_evse() -> Topics {
+        Topics::PerEvse {
+            prefix: "dehneEVSE".to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_commands_from_the_payload() {
+        let msg = single()
+            .parse(
+                "to_dehneEVSE",
+                r#"{"message_type": "request_set_pwm_percent", "client_id": "garage", "pwm_percent": 50}"#,
+            )
+            .unwrap();
+        assert_eq!(msg.message_type, MqttMessageType::request_set_pwm_percent);
+        assert_eq!(msg.client_id, "garage");
+        assert_eq!(msg.pwm_percent, Some(50));
+        assert_eq!(single().publish_topic(&msg), "from_dehneEVSE");
+    }
+
+    #[test]
+    fn parses_commands_from_the_topic() {
+        let topics = per_evse();
+        let msg = topics
+            .parse(
+                "dehneEVSE/garage/cmd/set_pwm_percent",
+                r#"{"pwm_percent": 50}"#,
+            )
+            .unwrap();
+        assert_eq!(msg.message_type, MqttMessageType::request_set_pwm_percent);
+        assert_eq!(msg.client_id, "garage");
+        assert_eq!(msg.pwm_percent, Some(50));
+        assert_eq!(
+            topics.command_topic("garage", "set_pwm_percent"),
+            "dehneEVSE/garage/cmd/set_pwm_percent"
+        );
+
+        // the topic wins over the payload, which may be empty
+        let msg = topics
+            .parse("dehneEVSE/garage/cmd/ping", r#"{"client_id": "carport"}"#)
+            .unwrap();
+        assert_eq!(msg.message_type, MqttMessageType::request_ping);
+        assert_eq!(msg.client_id, "garage");
+        assert!(topics.parse("dehneEVSE/garage/cmd/ping", "").is_ok());
+    }
+
+    #[test]
+    fn publishes_per_evse() {
+        let topics = per_evse();
+        let msg = |message_type| MqttMessage::new(message_type, "garage".to_string());
+        assert_eq!(
+            topics.publish_topic(&msg(MqttMessageType::response_collect_data)),
+            "dehneEVSE/garage/state"
+        );
+        assert_eq!(
+            topics.publish_topic(&msg(MqttMessageType::response_ping)),
+            "dehneEVSE/garage/response/ping"
+        );
+        assert_eq!(
+            topics.publish_topic(&msg(MqttMessageType::new_connection)),
+            "dehneEVSE/garage/event/new_connection"
+        );
+        assert_eq!(
+            topics.availability_topic("garage"),
+            "dehneEVSE/garage/availability"
+        );
+        assert_eq!(
+            single().availability_topic("garage"),
+            "from_dehneEVSE/garage/availability"
+        );
+        assert_eq!(
+            topics.availability_client_id("dehneEVSE/garage/availability"),
+            Some("garage")
+        );
+        assert_eq!(topics.availability_client_id("dehneEVSE/garage/state"), None);
+    }
+
+    #[test]
+    fn reports_unknown_commands() {
+        let topics = per_evse();
+        let topic = "dehneEVSE/garage/cmd/reboot";
+        let err = topics.parse(topic, "").unwrap_err();
+        let msg = topics.parse_error(topic, r#"{"request_id": "1"}"#, err.to_string());
+        assert_eq!(msg.client_id, "garage");
+        assert_eq!(msg.request_id.as_deref(), Some("1"));
+        let error = msg.error.as_ref().unwrap();
+        assert_eq!(error.code, ErrorCode::unknown_message_type);
+        assert_eq!(error.message_type.as_deref(), Some("request_reboot"));
+        assert_eq!(topics.publish_topic(&msg), "dehneEVSE/garage/event/error");
+
+        let payload = r#"{"message_type": "request_reboot", "client_id": "garage"}"#;
+        let msg = single().parse_error("to_dehneEVSE", payload, "unknown".to_string());
+        assert_eq!(msg.client_id, "garage");
+        assert_eq!(msg.error.unwrap().code, ErrorCode::unknown_message_type);
+    }
+
+    #[test]
+    fn reports_invalid_payloads() {
+        let topics = per_evse();
+        let topic = "dehneEVSE/garage/cmd/set_pwm_percent";
+        assert!(topics.parse(topic, "50").is_err());
+        let err = topics.parse(topic, "{").unwrap_err();
+        let msg = topics.parse_error(topic, "{", err.to_string());
+        assert_eq!(msg.error.unwrap().code, ErrorCode::invalid_json);
+    }
+
+    #[test]
+    fn rejects_malformed_topics() {
+        let topics = per_evse();
+        for topic in [
+            "dehneEVSE/garage/ping",
+            "dehneEVSE//cmd/ping",
+            "dehneEVSE/garage/cmd/",
+            "dehneEVSE/garage/cmd/set/ping",
+            "other/garage/cmd/ping",
+        ] {
+            assert!(topics.parse(topic, "").is_err(), "{}", topic);
+        }
+    }
+
+    #[test]
+    fn publishes_errors_without_a_charging_station_on_the_bridge_topic() {
+        let topics = per_evse();
+        let topic = "dehneEVSE//cmd/ping";
+        let msg = topics.parse_error(topic, "", "Unexpected command topic".to_string());
+        assert_eq!(msg.client_id, "");
+        assert_eq!(topics.publish_topic(&msg), "dehneEVSE/error");
+    }
+}
@@ -1,4 +1,8 @@
 use std::sync::{Mutex, Condvar, Arc};
+use std::time::Duration;
+use config::{Config, ConfigError};
+use serde::Deserialize;
+use serde_json::Value;
 
 pub fn bytes_to_hex(bytes: &[u8]) -> String {
     use std::fmt::Write;
@@ -9,6 +13,54 @@ pub fn bytes_to_hex(bytes: &[u8]) -> String {
     s
 }
 
+/// Reads `evse.id_<serial>.<key>` and falls back to the site-wide `evse.<key>`.
+pub fn evse_setting<'de, T: Deserialize<'de>>(
+    settings: &Config,
+    client_serial: &str,
+    key: &str,
+) -> Result<T, ConfigError> {
+    settings
+        .get::<T>(&format!("evse.id_{}.{}", client_serial, key))
+        .or_else(|_| settings.get::<T>(&format!("evse.{}", key)))
+}
+
+/// The serials with settings of their own in `[ evse.id_<serial> ]`.
+pub fn configured_serials(settings: &Config) -> Vec<String> {
+    let mut serials: Vec<String> = settings
+        .get_table("evse")
+        .map(|evse| {
+            evse.into_keys()
+                .filter_map(|key| key.strip_prefix("id_").map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+    serials.sort();
+    serials
+}
+
+/// Reads a number from an MQTT payload, either the plain payload or the value at the given
+/// JSON pointer (e.g. `/current/l1`) of a JSON payload.
+pub fn read_number(payload: &str, json_pointer: Option<&str>) -> Option<f64> {
+    match json_pointer {
+        None => payload.trim().parse::<f64>().ok(),
+        Some(pointer) => serde_json::from_str::<Value>(payload)
+            .ok()
+            .and_then(|json| json.pointer(pointer).and_then(Value::as_f64)),
+    }
+}
+
+/// Gaps between two data collections longer than this are not counted as charged energy.
+pub const MAX_MEASUREMENT_GAP: Duration = Duration::from_secs(300);
+
+/// Energy in kWh charged between two data collections `elapsed` apart, with the power in watts
+/// of both, by the trapezoidal rule. Gaps longer than [`MAX_MEASUREMENT_GAP`] count as nothing.
+pub fn energy_kwh(previous_watts: f32, watts: f32, elapsed: Duration) -> f64 {
+    if elapsed >= MAX_MEASUREMENT_GAP {
+        return 0.0;
+    }
+    (previous_watts + watts) as f64 / 2.0 / 1000.0 * elapsed.as_secs_f64() / 3600.0
+}
+
 pub trait CountDownLatch {
     fn count_up(&self);
     fn count_down(&self);
@@ -56,4 +108,39 @@ impl CountDownLatch for USizeCountDownLatch {
             counter = cvar.wait(counter).unwrap();
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integrates_energy_between_data_collections() {
+        // 11kW for half an hour after a cold start from 0W
+        let kwh = energy_kwh(0.0, 11000.0, Duration::from_secs(60))
+            + (0..29)
+                .map(|_| energy_kwh(11000.0, 11000.0, Duration::from_secs(60)))
+                .sum::<f64>();
+        assert!((kwh - 5.408).abs() < 0.001, "{}", kwh);
+    }
+
+    #[test]
+    fn skips_gaps_between_data_collections() {
+        assert_eq!(energy_kwh(11000.0, 11000.0, MAX_MEASUREMENT_GAP), 0.0);
+    }
+
+    #[test]
+    fn lists_serials_with_settings_of_their_own() {
+        let settings = Config::builder()
+            .set_override("evse.priority", 1)
+            .unwrap()
+            .set_override("evse.id_795af521.priority", 2)
+            .unwrap()
+            .set_override("evse.id_10ba23ab.schedule.enabled", true)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(configured_serials(&settings), vec!["10ba23ab", "795af521"]);
+        assert!(configured_serials(&Config::default()).is_empty());
+    }
+}